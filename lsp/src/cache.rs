@@ -0,0 +1,567 @@
+use anyhow::{anyhow, Result};
+use fs2::FileExt;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::render::{active_renderer, mmdc_version, RenderOptions, SANITIZER_VERSION};
+use crate::MERMAID_CACHE_DIR;
+
+/// Version of the cache key scheme and layout. Bumping it orphans every existing entry.
+pub const CACHE_VERSION: u32 = 1;
+
+/// File in the versioned cache directory recording when each entry was last used.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Lock file serializing manifest updates and eviction between processes.
+const LOCK_FILE: &str = ".lock";
+
+/// Directory under the user cache directory used when `sharedCache` is enabled.
+const SHARED_CACHE_DIR: &str = "mermaid-lsp";
+
+/// Distinguishes temporary files written concurrently by threads of this process.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Minimum time between opportunistic evictions of the same cache directory.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// When each cache directory was last evicted by this process.
+static LAST_EVICTION: Lazy<Mutex<HashMap<PathBuf, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Minimum time between manifest writes recording cache hits in the same cache directory.
+const ACCESS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Access times not yet written to the manifest, by media directory.
+static PENDING_ACCESSES: Lazy<Mutex<HashMap<PathBuf, PendingAccesses>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default)]
+struct PendingAccesses {
+    entries: BTreeMap<String, u64>,
+    last_flush: Option<Instant>,
+}
+
+/// Detected once per process: running `mmdc --version` starts Node.
+static MMDC_VERSION: Lazy<String> = Lazy::new(|| {
+    mmdc_version().unwrap_or_else(|e| {
+        warn!("Could not detect mmdc version for the render cache: {}", e);
+        "unknown".to_string()
+    })
+});
+
+/// Stable key for a rendered diagram.
+///
+/// Covers everything that can change the output: the source, the effective Mermaid
+/// config and background, the renderer and mmdc version, the sanitizer and the format
+/// (with its scale, for PNG).
+pub fn cache_key(code: &str, options: &RenderOptions, format: &str) -> String {
+    let renderer = active_renderer();
+    // Only raster output depends on the scale factor
+    let scale = if format == "png" { options.scale.to_string() } else { String::new() };
+    let fields = [
+        CACHE_VERSION.to_string(),
+        code.to_string(),
+        options.config.clone(),
+        options.background.clone(),
+        renderer.name().to_string(),
+        MMDC_VERSION.clone(),
+        SANITIZER_VERSION.to_string(),
+        format.to_string(),
+        scale,
+    ];
+
+    let mut hasher = Sha256::new();
+    for field in &fields {
+        hasher.update(field.as_bytes());
+        // Field separator, so adjacent fields cannot run into each other
+        hasher.update([0u8]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Cache directory serving documents whose assets live in `media_dir`.
+///
+/// With `sharedCache` enabled this is one user-level directory (`$XDG_CACHE_HOME/mermaid-lsp`
+/// on Linux) shared by every workspace; otherwise it is `<media_dir>/.cache`.
+pub fn cache_root(media_dir: &Path) -> PathBuf {
+    if crate::config::current().shared_cache {
+        match dirs::cache_dir() {
+            Some(dir) => return dir.join(SHARED_CACHE_DIR),
+            None => warn!("No user cache directory available; using the per-folder cache"),
+        }
+    }
+    media_dir.join(MERMAID_CACHE_DIR)
+}
+
+/// Versioned directory holding the entries of the current cache scheme.
+pub fn cache_dir(media_dir: &Path) -> PathBuf {
+    cache_root(media_dir).join(format!("v{}", CACHE_VERSION))
+}
+
+/// Location of a cache entry: `<cache root>/v<version>/<key>.<format>`.
+pub fn cache_path(media_dir: &Path, key: &str, format: &str) -> PathBuf {
+    cache_dir(media_dir).join(format!("{}.{}", key, format))
+}
+
+/// Write a file so that concurrent readers see either nothing or the complete contents.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid cache path: {:?}", path))?
+        .to_string_lossy();
+    let temp = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    fs::write(&temp, contents).map_err(|e| anyhow!("Failed to write {:?}: {}", temp, e))?;
+    fs::rename(&temp, path).map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        anyhow!("Failed to move {:?} into place: {}", path, e)
+    })
+}
+
+/// Take the cache's exclusive lock; it is released when the returned file is dropped.
+///
+/// Locking is best effort: if it fails the caller proceeds unlocked rather than not at all.
+fn lock_cache(media_dir: &Path) -> Option<fs::File> {
+    let dir = cache_dir(media_dir);
+    fs::create_dir_all(&dir).ok()?;
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))
+        .ok()?;
+
+    match file.lock_exclusive() {
+        Ok(()) => Some(file),
+        Err(e) => {
+            warn!("Failed to lock render cache {:?}: {}", dir, e);
+            None
+        }
+    }
+}
+
+/// Bounds enforced on each cache directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheLimits {
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 100 * 1024 * 1024,
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Last-access times of cache entries, keyed by file name, in seconds since the epoch.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    entries: BTreeMap<String, u64>,
+}
+
+impl Manifest {
+    fn load(media_dir: &Path) -> Self {
+        fs::read_to_string(cache_dir(media_dir).join(MANIFEST_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Merge access times in, keeping the later time for entries recorded twice.
+    fn record(&mut self, accesses: BTreeMap<String, u64>) {
+        for (name, time) in accesses {
+            let last_access = self.entries.entry(name).or_insert(time);
+            *last_access = (*last_access).max(time);
+        }
+    }
+
+    fn save(&self, media_dir: &Path) -> Result<()> {
+        let path = cache_dir(media_dir).join(MANIFEST_FILE);
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| anyhow!("Failed to serialize cache manifest: {}", e))?;
+        write_atomic(&path, contents.as_bytes())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Record that a cache entry was just read or written.
+///
+/// Access times are kept in memory and written to the manifest at most once per
+/// [`ACCESS_FLUSH_INTERVAL`], on eviction, or by [`flush_accesses`].
+pub fn record_access(media_dir: &Path, entry: &Path) {
+    let Some(name) = entry.file_name().map(|name| name.to_string_lossy().to_string()) else {
+        return;
+    };
+
+    let due = match PENDING_ACCESSES.lock() {
+        Ok(mut pending) => {
+            let accesses = pending.entry(media_dir.to_path_buf()).or_default();
+            accesses.entries.insert(name, now_secs());
+            accesses
+                .last_flush
+                .map(|at| at.elapsed() >= ACCESS_FLUSH_INTERVAL)
+                .unwrap_or(true)
+        }
+        Err(_) => false,
+    };
+    if due {
+        flush_accesses_for(media_dir);
+    }
+}
+
+/// Access times recorded for `media_dir` since they were last written, forgetting them.
+fn take_accesses(media_dir: &Path) -> BTreeMap<String, u64> {
+    let Ok(mut pending) = PENDING_ACCESSES.lock() else {
+        return BTreeMap::new();
+    };
+    let accesses = pending.entry(media_dir.to_path_buf()).or_default();
+    accesses.last_flush = Some(Instant::now());
+    std::mem::take(&mut accesses.entries)
+}
+
+fn flush_accesses_for(media_dir: &Path) {
+    let accesses = take_accesses(media_dir);
+    // A cleared cache has nothing left to record
+    if accesses.is_empty() || !cache_dir(media_dir).is_dir() {
+        return;
+    }
+
+    let _lock = lock_cache(media_dir);
+    let mut manifest = Manifest::load(media_dir);
+    manifest.record(accesses);
+    if let Err(e) = manifest.save(media_dir) {
+        warn!("{}", e);
+    }
+}
+
+/// Write every access time still held in memory, e.g. when the server shuts down.
+pub fn flush_accesses() {
+    let media_dirs: Vec<PathBuf> = match PENDING_ACCESSES.lock() {
+        Ok(pending) => pending.keys().cloned().collect(),
+        Err(_) => return,
+    };
+    for media_dir in media_dirs {
+        flush_accesses_for(&media_dir);
+    }
+}
+
+/// Evict after a render, at most once per [`EVICTION_INTERVAL`] for each cache directory.
+pub fn maybe_evict(media_dir: &Path, limits: CacheLimits) {
+    if let Ok(mut last) = LAST_EVICTION.lock() {
+        let due = last
+            .get(media_dir)
+            .map(|at| at.elapsed() >= EVICTION_INTERVAL)
+            .unwrap_or(true);
+        if !due {
+            return;
+        }
+        last.insert(media_dir.to_path_buf(), Instant::now());
+    }
+
+    if let Err(e) = evict(media_dir, limits) {
+        warn!("Cache eviction failed for {:?}: {}", media_dir, e);
+    }
+}
+
+/// Enforce `limits` on the cache under `media_dir`, returning the number of files removed.
+///
+/// Entries older than `max_age` and entries from older cache versions are removed first;
+/// then the least recently used entries go until the cache fits in `max_bytes`.
+pub fn evict(media_dir: &Path, limits: CacheLimits) -> Result<usize> {
+    let root = cache_root(media_dir);
+    if !root.is_dir() {
+        return Ok(0);
+    }
+
+    let _lock = lock_cache(media_dir);
+    let mut removed = 0;
+    let current = cache_dir(media_dir);
+
+    // Anything outside the current version's directory can never be hit again
+    for entry in fs::read_dir(&root).map_err(|e| anyhow!("Failed to read {:?}: {}", root, e))?.flatten() {
+        let path = entry.path();
+        if path == current {
+            continue;
+        }
+        removed += count_files(&path).max(1);
+        let result = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        if let Err(e) = result {
+            warn!("Failed to remove stale cache entry {:?}: {}", path, e);
+        }
+    }
+
+    let mut manifest = Manifest::load(media_dir);
+    manifest.record(take_accesses(media_dir));
+    let mut entries: Vec<(u64, u64, PathBuf, String)> = Vec::new();
+    if let Ok(files) = fs::read_dir(&current) {
+        for file in files.flatten() {
+            let path = file.path();
+            let name = file.file_name().to_string_lossy().to_string();
+            let Ok(metadata) = file.metadata() else { continue };
+            // Skips the lock file and other processes' in-flight temporary files
+            if name == MANIFEST_FILE || name.starts_with('.') || !metadata.is_file() {
+                continue;
+            }
+
+            // Files the manifest doesn't know yet count as used when last written
+            let last_access = manifest.entries.get(&name).copied().unwrap_or_else(|| {
+                metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+            });
+            entries.push((last_access, metadata.len(), path, name));
+        }
+    }
+
+    // Least recently used first
+    entries.sort();
+    let oldest_allowed = now_secs().saturating_sub(limits.max_age.as_secs());
+    let mut total: u64 = entries.iter().map(|(_, size, _, _)| size).sum();
+    let mut kept = BTreeMap::new();
+
+    for (last_access, size, path, name) in entries {
+        if last_access < oldest_allowed || total > limits.max_bytes {
+            debug!("Evicting cached diagram {:?}", path);
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    removed += 1;
+                    total = total.saturating_sub(size);
+                    continue;
+                }
+                Err(e) => warn!("Failed to evict {:?}: {}", path, e),
+            }
+        }
+        kept.insert(name, last_access);
+    }
+
+    manifest.entries = kept;
+    if current.is_dir() {
+        manifest.save(media_dir)?;
+    }
+
+    if removed > 0 {
+        info!("Evicted {} cached diagram(s) from {:?}", removed, root);
+    }
+    Ok(removed)
+}
+
+/// Delete every cached render serving `media_dir`, including entries from older
+/// cache versions. Returns the number of files removed.
+///
+/// The cache stays locked while it is cleared. The lock file itself and other processes'
+/// in-flight temporary files are kept, so servers sharing the cache are not disrupted.
+pub fn clear_cache(media_dir: &Path) -> Result<usize> {
+    let root = cache_root(media_dir);
+    if !root.is_dir() {
+        return Ok(0);
+    }
+
+    let _lock = lock_cache(media_dir);
+    let current = cache_dir(media_dir);
+    let mut removed = 0;
+
+    for entry in fs::read_dir(&root).map_err(|e| anyhow!("Failed to read {:?}: {}", root, e))?.flatten() {
+        let path = entry.path();
+        if path == current {
+            continue;
+        }
+        removed += count_files(&path).max(1);
+        let result = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        result.map_err(|e| anyhow!("Failed to clear cache entry {:?}: {}", path, e))?;
+    }
+
+    if let Ok(files) = fs::read_dir(&current) {
+        for file in files.flatten() {
+            let path = file.path();
+            let name = file.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || !path.is_file() {
+                continue;
+            }
+            fs::remove_file(&path).map_err(|e| anyhow!("Failed to clear cache entry {:?}: {}", path, e))?;
+            if name != MANIFEST_FILE {
+                removed += 1;
+            }
+        }
+    }
+
+    info!("Cleared {} cached diagram(s) from {:?}", removed, root);
+    Ok(removed)
+}
+
+fn count_files(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| {
+                    let path = entry.path();
+                    if path.is_dir() {
+                        count_files(&path)
+                    } else {
+                        1
+                    }
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_changes_with_every_input() {
+        let options = RenderOptions::default();
+        let key = cache_key("pie", &options, "svg");
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, cache_key("pie", &options, "svg"));
+
+        assert_ne!(key, cache_key("pie\n", &options, "svg"));
+        assert_ne!(key, cache_key("pie", &options, "png"));
+
+        let dark = RenderOptions {
+            background: "transparent".to_string(),
+            ..RenderOptions::default()
+        };
+        assert_ne!(key, cache_key("pie", &dark, "svg"));
+
+        // Scale only matters for PNG
+        let scaled = RenderOptions { scale: 2.0, ..RenderOptions::default() };
+        assert_eq!(key, cache_key("pie", &scaled, "svg"));
+        assert_ne!(cache_key("pie", &options, "png"), cache_key("pie", &scaled, "png"));
+    }
+
+    fn write_entry(media_dir: &Path, key: &str, bytes: usize) -> PathBuf {
+        let path = cache_path(media_dir, key, "svg");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "x".repeat(bytes)).unwrap();
+        path
+    }
+
+    #[test]
+    fn evicts_least_recently_used_beyond_size_limit() {
+        let media_dir = tempfile::tempdir().unwrap();
+        let media_dir = media_dir.path();
+        let old = write_entry(media_dir, "old", 100);
+        let recent = write_entry(media_dir, "recent", 100);
+        let newest = write_entry(media_dir, "newest", 100);
+
+        let mut manifest = Manifest::default();
+        manifest.entries.insert("old.svg".to_string(), now_secs() - 30);
+        manifest.entries.insert("recent.svg".to_string(), now_secs() - 20);
+        manifest.entries.insert("newest.svg".to_string(), now_secs() - 10);
+        manifest.save(media_dir).unwrap();
+
+        let limits = CacheLimits { max_bytes: 250, ..CacheLimits::default() };
+        assert_eq!(evict(media_dir, limits).unwrap(), 1);
+        assert!(!old.exists());
+        assert!(recent.exists() && newest.exists());
+
+        // Reading an entry makes it the most recently used
+        record_access(media_dir, &recent);
+        let limits = CacheLimits { max_bytes: 150, ..CacheLimits::default() };
+        assert_eq!(evict(media_dir, limits).unwrap(), 1);
+        assert!(recent.exists());
+        assert!(!newest.exists());
+        assert_eq!(Manifest::load(media_dir).entries.len(), 1);
+    }
+
+    #[test]
+    fn writes_access_times_at_most_once_per_interval() {
+        let media_dir = tempfile::tempdir().unwrap();
+        let media_dir = media_dir.path();
+        let first = write_entry(media_dir, "first", 10);
+        let second = write_entry(media_dir, "second", 10);
+
+        record_access(media_dir, &first);
+        record_access(media_dir, &second);
+        let manifest = Manifest::load(media_dir);
+        assert!(manifest.entries.contains_key("first.svg"));
+        assert!(!manifest.entries.contains_key("second.svg"));
+
+        flush_accesses();
+        assert!(Manifest::load(media_dir).entries.contains_key("second.svg"));
+    }
+
+    #[test]
+    fn evicts_expired_and_legacy_entries() {
+        let media_dir = tempfile::tempdir().unwrap();
+        let media_dir = media_dir.path();
+        let expired = write_entry(media_dir, "expired", 10);
+        let fresh = write_entry(media_dir, "fresh", 10);
+        let legacy = media_dir.join(MERMAID_CACHE_DIR).join("mermaid_1f.svg");
+        fs::write(&legacy, "<svg/>").unwrap();
+
+        let mut manifest = Manifest::default();
+        manifest.entries.insert("expired.svg".to_string(), now_secs() - 3 * 24 * 60 * 60);
+        manifest.save(media_dir).unwrap();
+
+        let limits = CacheLimits { max_age: Duration::from_secs(24 * 60 * 60), ..CacheLimits::default() };
+        assert_eq!(evict(media_dir, limits).unwrap(), 2);
+        assert!(!expired.exists() && !legacy.exists());
+        assert!(fresh.exists());
+    }
+
+    #[test]
+    fn atomic_writes_leave_no_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entry.svg");
+        write_atomic(&path, b"<svg/>").unwrap();
+        write_atomic(&path, b"<svg></svg>").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "<svg></svg>");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn clears_current_and_legacy_entries() {
+        let media_dir = tempfile::tempdir().unwrap();
+        let current = cache_path(media_dir.path(), "abc", "svg");
+        fs::create_dir_all(current.parent().unwrap()).unwrap();
+        fs::write(&current, "<svg/>").unwrap();
+        fs::write(media_dir.path().join(MERMAID_CACHE_DIR).join("mermaid_1f.svg"), "<svg/>").unwrap();
+
+        assert_eq!(clear_cache(media_dir.path()).unwrap(), 2);
+        // Only the lock file is left, for servers sharing the cache
+        let remaining: Vec<_> = fs::read_dir(cache_dir(media_dir.path())).unwrap().flatten().collect();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].file_name(), LOCK_FILE);
+        assert_eq!(fs::read_dir(media_dir.path().join(MERMAID_CACHE_DIR)).unwrap().count(), 1);
+        assert_eq!(clear_cache(media_dir.path()).unwrap(), 0);
+    }
+}
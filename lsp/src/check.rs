@@ -0,0 +1,369 @@
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::cli::document_files;
+use crate::diagnostics::{diagnostic_for_error, find_diagram_sources, summarize_error};
+use crate::render::render_mermaid;
+use crate::{
+    content_hash, count_rendered_blocks, decode_data_uri, is_mermaid_document, recorded_image_source_hash,
+    rendered_block_source, rendered_images, referenced_media_files, CONTENT_HASH_LENGTH, MERMAID_MEDIA_DIR,
+};
+
+/// Extensions of generated files that belong in a `.mermaid` directory.
+const MEDIA_EXTENSIONS: &[&str] = &["mmd", "svg", "png"];
+
+/// A problem found by `mermaid-lsp check`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub path: PathBuf,
+    /// 1-based line, when the problem is tied to one.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// Totals reported alongside the problems.
+#[derive(Debug, Default)]
+pub struct CheckSummary {
+    pub documents: usize,
+    pub fences: usize,
+    pub rendered: usize,
+}
+
+/// `mermaid-lsp check [paths...]`: report broken or stale diagrams and exit non-zero if any.
+pub fn check_command(args: &[String]) -> Result<i32> {
+    let paths: Vec<PathBuf> = if args.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        args.iter().map(PathBuf::from).collect()
+    };
+
+    let (problems, summary) = check_paths(&paths)?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    eprintln!(
+        "Checked {} document(s): {} diagram fence(s), {} rendered diagram(s), {} problem(s)",
+        summary.documents,
+        summary.fences,
+        summary.rendered,
+        problems.len()
+    );
+
+    Ok(if problems.is_empty() { 0 } else { 1 })
+}
+
+/// Check every Markdown and `.mmd` document under `paths`.
+pub fn check_paths(paths: &[PathBuf]) -> Result<(Vec<Problem>, CheckSummary)> {
+    let mut problems = Vec::new();
+    let mut summary = CheckSummary::default();
+    // References from every document, grouped by the directory owning the .mermaid folder
+    let mut references: BTreeMap<PathBuf, HashSet<String>> = BTreeMap::new();
+
+    for file in document_files(paths)? {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) => {
+                problems.push(Problem {
+                    path: file.clone(),
+                    line: None,
+                    message: format!("failed to read: {}", e),
+                });
+                continue;
+            }
+        };
+
+        summary.documents += 1;
+        summary.rendered += count_rendered_blocks(&content);
+
+        let parent = file.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
+        summary.fences += check_fences(&file, &content, is_mermaid_document(&file.to_string_lossy()), &mut problems);
+        check_rendered_blocks(&file, &parent, &content, &mut problems);
+
+        references
+            .entry(parent)
+            .or_default()
+            .extend(referenced_media_files(&content));
+    }
+
+    for (dir, referenced) in &references {
+        check_orphans(dir, referenced, &mut problems);
+    }
+
+    Ok((problems, summary))
+}
+
+/// Render every unrendered fence, or an unrendered `.mmd` document; returns the number checked.
+fn check_fences(file: &Path, content: &str, is_mermaid: bool, problems: &mut Vec<Problem>) -> usize {
+    let lines: Vec<&str> = content.lines().collect();
+    // A rendered .mmd document holds a source comment and image, checked with the rendered blocks
+    let sources = if is_mermaid && count_rendered_blocks(content) > 0 {
+        Vec::new()
+    } else {
+        find_diagram_sources(content, is_mermaid)
+    };
+
+    for source in &sources {
+        if source.code.trim().is_empty() {
+            problems.push(Problem {
+                path: file.to_path_buf(),
+                line: Some(source.fence_line + 1),
+                message: "empty mermaid block".to_string(),
+            });
+            continue;
+        }
+
+        if let Err(e) = render_mermaid(&source.code) {
+            let error = e.to_string();
+            let diagnostic = diagnostic_for_error(source, &lines, &error);
+            problems.push(Problem {
+                path: file.to_path_buf(),
+                line: Some(diagnostic.range.start.line as usize + 1),
+                message: summarize_error(&error).replace('\n', " "),
+            });
+        }
+    }
+
+    sources.len()
+}
+
+/// Verify the source of each rendered block (a `.mmd` file or inline) and the images that follow it.
+fn check_rendered_blocks(file: &Path, parent: &Path, content: &str, problems: &mut Vec<Problem>) {
+    let lines: Vec<&str> = content.lines().collect();
+
+    for index in 0..lines.len() {
+        let Some((block_source, source_end)) = rendered_block_source(&lines, index) else {
+            continue;
+        };
+        let problem = |message: String| Problem {
+            path: file.to_path_buf(),
+            line: Some(index + 1),
+            message,
+        };
+
+        let source = match block_source.read(parent) {
+            Ok(source) => source,
+            Err(_) => {
+                problems.push(problem(format!("source file {} is missing", block_source.describe())));
+                continue;
+            }
+        };
+
+        // The image link(s) follow the comment; a dark rendition is checked like the light one
+        let images = rendered_images(&lines, source_end);
+        if images.paths.is_empty() {
+            problems.push(problem("rendered block has no image link".to_string()));
+            continue;
+        }
+
+        for image_path in images.paths {
+            let image_index = (index + 1..lines.len())
+                .find(|&i| lines[i].contains(image_path))
+                .unwrap_or(index);
+
+            // Embedded images are checked in place
+            let image = match decode_data_uri(image_path) {
+                Some(image) => Some(image),
+                None => fs::read(parent.join(image_path)).ok(),
+            };
+            let image_name = if image_path.starts_with("data:") { "<embedded>" } else { image_path };
+            let Some(image) = image else {
+                problems.push(Problem {
+                    path: file.to_path_buf(),
+                    line: Some(image_index + 1),
+                    message: format!("rendered image '{}' is missing", image_name),
+                });
+                continue;
+            };
+
+            // Images rendered before the hash was stamped into them carry it in their name
+            let expected = content_hash(&source);
+            let recorded = recorded_image_source_hash(&image).or_else(|| filename_hash(image_path));
+            if let Some(recorded) = recorded {
+                if recorded != expected {
+                    problems.push(Problem {
+                        path: file.to_path_buf(),
+                        line: Some(image_index + 1),
+                        message: format!(
+                            "rendered image '{}' is stale: {} has changed since it was rendered",
+                            image_name,
+                            block_source.describe()
+                        ),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Content hash embedded in a `<stem>_<diagram>_<hash>.svg` filename.
+fn filename_hash(image_path: &str) -> Option<&str> {
+    let stem = Path::new(image_path).file_stem()?.to_str()?;
+    let (_, hash) = stem.rsplit_once('_')?;
+    let is_hash = hash.len() == CONTENT_HASH_LENGTH && hash.chars().all(|c| c.is_ascii_hexdigit());
+    is_hash.then_some(hash)
+}
+
+/// Report generated files in `<dir>/.mermaid` that no document in `dir` refers to.
+fn check_orphans(dir: &Path, referenced: &HashSet<String>, problems: &mut Vec<Problem>) {
+    let media_dir = dir.join(MERMAID_MEDIA_DIR);
+    let Ok(entries) = fs::read_dir(&media_dir) else {
+        return;
+    };
+
+    let mut orphans: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| MEDIA_EXTENSIONS.contains(&ext))
+                .unwrap_or(false)
+        })
+        .filter(|path| {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let relative = format!("{}/{}", MERMAID_MEDIA_DIR, file_name);
+            !referenced.contains(file_name.as_ref()) && !referenced.contains(&relative)
+        })
+        .collect();
+    orphans.sort();
+
+    for path in orphans {
+        problems.push(Problem {
+            path,
+            line: None,
+            message: "orphaned file: not referenced by any document".to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stamp_source_hash;
+
+    #[test]
+    fn reads_hash_from_content_addressed_names() {
+        assert_eq!(filename_hash(".mermaid/doc_0_0123456789ab.svg"), Some("0123456789ab"));
+        assert_eq!(filename_hash(".mermaid/doc_diagram_1761843815_0.svg"), None);
+    }
+
+    #[test]
+    fn reports_missing_stale_and_orphaned_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir_all(&media).unwrap();
+
+        // Up to date
+        fs::write(media.join("doc_0_aaa.mmd"), "pie").unwrap();
+        fs::write(media.join("doc_0_aaa.svg"), stamp_source_hash("<svg/>", &content_hash("pie"))).unwrap();
+        // Source edited after rendering
+        fs::write(media.join("doc_1_bbb.mmd"), "flowchart TD\n    A --> C").unwrap();
+        fs::write(media.join("doc_1_bbb.svg"), stamp_source_hash("<svg/>", &content_hash("flowchart TD"))).unwrap();
+        // Nobody links to this one
+        fs::write(media.join("old_0_ccc.svg"), "<svg/>").unwrap();
+
+        let doc = dir.path().join("doc.md");
+        fs::write(
+            &doc,
+            "# Doc\n\
+             <!-- mermaid-source-file:.mermaid/doc_0_aaa.mmd-->\n\n\
+             ![Mermaid Diagram](.mermaid/doc_0_aaa.svg)\n\n\
+             <!-- mermaid-source-file:.mermaid/doc_1_bbb.mmd-->\n\n\
+             ![Mermaid Diagram](.mermaid/doc_1_bbb.svg)\n\n\
+             <!-- mermaid-source-file:.mermaid/doc_2_gone.mmd-->\n\n\
+             ![Mermaid Diagram](.mermaid/doc_2_gone.svg)\n",
+        )
+        .unwrap();
+
+        let (problems, summary) = check_paths(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(summary.documents, 1);
+        assert_eq!(summary.rendered, 3);
+
+        let messages: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems.len(), 3, "{:#?}", messages);
+        assert_eq!(problems[0].line, Some(8));
+        assert!(problems[0].message.contains("stale"));
+        assert_eq!(problems[1].line, Some(10));
+        assert!(problems[1].message.contains("missing"));
+        assert!(problems[2].path.ends_with("old_0_ccc.svg"));
+        assert!(problems[2].message.contains("orphaned"));
+    }
+
+    #[test]
+    fn checks_mmd_documents() {
+        crate::render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir_all(&media).unwrap();
+        fs::write(dir.path().join("flow.mmd"), "flowchart TD\n    A --> B\n").unwrap();
+
+        // Only a rendered .mmd document refers to these
+        fs::write(media.join("seq_0_aaa.mmd"), "pie").unwrap();
+        fs::write(media.join("seq_0_aaa.svg"), stamp_source_hash("<svg/>", &content_hash("pie"))).unwrap();
+        fs::write(
+            dir.path().join("seq.mmd"),
+            "<!-- mermaid-source-file:.mermaid/seq_0_aaa.mmd-->\n\n![Mermaid Diagram](.mermaid/seq_0_aaa.svg)\n",
+        )
+        .unwrap();
+
+        let (problems, summary) = check_paths(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(summary.documents, 2);
+        assert_eq!(summary.fences, 1);
+        assert_eq!(summary.rendered, 1);
+        assert!(problems.is_empty(), "{:#?}", problems);
+    }
+
+    #[test]
+    fn checks_embedded_images_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir_all(&media).unwrap();
+        fs::write(media.join("doc_0_aaa.mmd"), "pie").unwrap();
+
+        let stale = stamp_source_hash("<svg/>", &content_hash("flowchart TD"));
+        let uri = crate::data_uri(crate::render::ImageFormat::Svg, stale.as_bytes());
+        fs::write(
+            dir.path().join("doc.md"),
+            format!("<!-- mermaid-source-file:.mermaid/doc_0_aaa.mmd-->\n\n![Mermaid Diagram]({})\n", uri),
+        )
+        .unwrap();
+
+        let (problems, _) = check_paths(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(3));
+        assert!(problems[0].message.contains("'<embedded>' is stale"));
+    }
+
+    #[test]
+    fn checks_inline_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir_all(&media).unwrap();
+        fs::write(media.join("doc_0_aaa.svg"), stamp_source_hash("<svg/>", &content_hash("pie"))).unwrap();
+        fs::write(
+            dir.path().join("doc.md"),
+            "<!-- mermaid-source\nflowchart TD\n-->\n\n![Mermaid Diagram](.mermaid/doc_0_aaa.svg)\n",
+        )
+        .unwrap();
+
+        let (problems, summary) = check_paths(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(summary.rendered, 1);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.contains("stale: the inline source has changed"));
+    }
+}
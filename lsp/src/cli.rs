@@ -0,0 +1,356 @@
+use anyhow::{anyhow, Result};
+use lsp_types::TextEdit;
+use similar::TextDiff;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::check::check_command;
+use crate::config::{self, Settings};
+use crate::render::{mmdc_version, renderer_from_name, set_renderer};
+use crate::{position_to_offset, render_all_diagrams, WriteMode};
+
+/// Directories never descended into when walking a tree for Markdown files.
+const SKIPPED_DIRS: &[&str] = &["node_modules", "target"];
+
+const USAGE: &str = "\
+Usage: mermaid-lsp [COMMAND]
+
+Without a command, runs the Mermaid language server over stdio.
+
+Commands:
+  render [--dry-run] <paths...>  Render every ```mermaid fence in Markdown files or directories
+  check [paths...]               Report broken, stale or orphaned diagrams in Markdown and .mmd
+                                 documents (exits 1 if any)
+
+Options:
+  --settings <json|file>         Settings as given to the editor's language server, inline or in a
+                                 JSON file, so output matches the editor (e.g. '{\"theme\": \"dark\"}')
+  -h, --help                     Print this help
+  -V, --version                  Print the server, mmdc and configuration versions
+";
+
+/// Run a command-line invocation and return the process exit code.
+pub fn run(args: &[String]) -> Result<i32> {
+    let (settings_arg, args) = match take_settings_arg(args) {
+        Ok(split) => split,
+        Err(e) => {
+            eprint!("{}\n\n{}", e, USAGE);
+            return Ok(2);
+        }
+    };
+    let settings = match settings_arg.as_deref().map(load_settings).transpose() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(2);
+        }
+    };
+    apply_settings(settings.unwrap_or_default())?;
+
+    match args.first().map(String::as_str) {
+        Some("render") => render_command(&args[1..]),
+        Some("check") => check_command(&args[1..]),
+        Some("--help") | Some("-h") | Some("help") => {
+            print!("{}", USAGE);
+            Ok(0)
+        }
+        Some("--version") | Some("-V") => {
+            print!("{}", version_text());
+            Ok(0)
+        }
+        Some(other) => {
+            eprint!("Unknown command '{}'\n\n{}", other, USAGE);
+            Ok(2)
+        }
+        None => {
+            eprint!("{}", USAGE);
+            Ok(2)
+        }
+    }
+}
+
+/// Split `--settings <value>` (anywhere in the arguments) from the rest.
+fn take_settings_arg(args: &[String]) -> Result<(Option<String>, Vec<String>)> {
+    let mut settings = None;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--settings" {
+            let value = args.next().ok_or_else(|| anyhow!("--settings needs a value"))?;
+            settings = Some(value.clone());
+        } else if let Some(value) = arg.strip_prefix("--settings=") {
+            settings = Some(value.to_string());
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    Ok((settings, rest))
+}
+
+/// Settings from inline JSON, or from the JSON file it names.
+fn load_settings(arg: &str) -> Result<Settings> {
+    let json = if arg.trim_start().starts_with('{') {
+        arg.to_string()
+    } else {
+        fs::read_to_string(arg).map_err(|e| anyhow!("Failed to read settings file {}: {}", arg, e))?
+    };
+    let value: serde_json::Value =
+        serde_json::from_str(&json).map_err(|e| anyhow!("Invalid --settings JSON: {}", e))?;
+
+    let (settings, warnings) = Settings::parse(Some(&value));
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(settings)
+}
+
+/// Make settings current for this run, including the renderer they (or MERMAID_RENDERER) select.
+fn apply_settings(settings: Settings) -> Result<()> {
+    set_renderer(renderer_from_name(&settings.renderer_name())?);
+    config::update(settings);
+    Ok(())
+}
+
+/// Output of `--version`: the server version, the mmdc it will run and the config hash.
+///
+/// A missing mmdc is reported rather than treated as a failure, since the binary itself works.
+fn version_text() -> String {
+    let mmdc = mmdc_version().unwrap_or_else(|e| format!("unavailable ({})", e));
+    format!(
+        "mermaid-lsp {}\nmmdc {}\nconfig {}\n",
+        env!("CARGO_PKG_VERSION"),
+        mmdc,
+        crate::config::current().render_options().config_hash()
+    )
+}
+
+/// `mermaid-lsp render [--dry-run] <paths...>`
+fn render_command(args: &[String]) -> Result<i32> {
+    let mut dry_run = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--dry-run" | "-n" => dry_run = true,
+            flag if flag.starts_with('-') => {
+                eprint!("Unknown option '{}'\n\n{}", flag, USAGE);
+                return Ok(2);
+            }
+            path => paths.push(PathBuf::from(path)),
+        }
+    }
+
+    if paths.is_empty() {
+        eprint!("render: no paths given\n\n{}", USAGE);
+        return Ok(2);
+    }
+
+    let mode = if dry_run { WriteMode::DryRun } else { WriteMode::Write };
+    let mut failed = false;
+
+    for file in markdown_files(&paths)? {
+        let content = fs::read_to_string(&file)
+            .map_err(|e| anyhow!("Failed to read {}: {}", file.display(), e))?;
+        let uri = file_uri(&file)?;
+
+        let (edits, failures) = render_all_diagrams(uri.as_str(), &content, mode);
+        for failure in &failures {
+            eprintln!("{}:{}: {}", file.display(), failure.line, failure.message);
+        }
+        failed |= !failures.is_empty();
+
+        let text_edits = edits.get(&uri).map(Vec::as_slice).unwrap_or_default();
+        if text_edits.is_empty() {
+            continue;
+        }
+
+        let rendered = apply_text_edits(&content, text_edits);
+        if dry_run {
+            let name = file.display().to_string();
+            print!(
+                "{}",
+                TextDiff::from_lines(&content, &rendered)
+                    .unified_diff()
+                    .header(&name, &name)
+            );
+        } else {
+            fs::write(&file, &rendered)
+                .map_err(|e| anyhow!("Failed to write {}: {}", file.display(), e))?;
+            println!("Rendered {} diagram(s) in {}", text_edits.len(), file.display());
+        }
+    }
+
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// Expand files and directories into the Markdown files they contain, in a stable order.
+pub fn markdown_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    files_matching(paths, is_markdown_file)
+}
+
+/// Like [`markdown_files`], also including standalone `.mmd` documents.
+pub fn document_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    files_matching(paths, |path| is_markdown_file(path) || crate::is_mermaid_document(&path.to_string_lossy()))
+}
+
+fn files_matching(paths: &[PathBuf], accept: fn(&Path) -> bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            collect_files(path, accept, &mut files)?;
+        } else if path.is_file() {
+            files.push(path.clone());
+        } else {
+            return Err(anyhow!("No such file or directory: {}", path.display()));
+        }
+    }
+    Ok(files)
+}
+
+fn collect_files(dir: &Path, accept: fn(&Path) -> bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| anyhow!("Failed to read directory {}: {}", dir.display(), e))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if path.is_dir() {
+            // Skips .git, .mermaid and other hidden directories
+            if !name.starts_with('.') && !SKIPPED_DIRS.contains(&name.as_str()) {
+                collect_files(&path, accept, files)?;
+            }
+        } else if accept(&path) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_markdown_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("md") | Some("markdown")
+    )
+}
+
+/// `file://` URI for a path, as the editor would send it.
+pub fn file_uri(path: &Path) -> Result<Url> {
+    let absolute = path
+        .canonicalize()
+        .map_err(|e| anyhow!("Failed to resolve {}: {}", path.display(), e))?;
+    Url::from_file_path(&absolute).map_err(|_| anyhow!("Invalid file path: {}", absolute.display()))
+}
+
+/// Apply non-overlapping text edits to a document.
+pub fn apply_text_edits(content: &str, edits: &[TextEdit]) -> String {
+    let mut sorted: Vec<&TextEdit> = edits.iter().collect();
+    sorted.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+
+    let mut result = content.to_string();
+    // Apply from the bottom up so earlier offsets stay valid
+    for edit in sorted.into_iter().rev() {
+        let start = position_to_offset(&edit.range.start, content).min(content.len());
+        let end = position_to_offset(&edit.range.end, content).min(content.len());
+        result.replace_range(start..end.max(start), &edit.new_text);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{Position, Range};
+
+    fn edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range {
+                start: Position { line: start.0, character: start.1 },
+                end: Position { line: end.0, character: end.1 },
+            },
+            new_text: new_text.to_string(),
+        }
+    }
+
+    #[test]
+    fn version_reports_crate_and_config() {
+        let text = version_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], format!("mermaid-lsp {}", env!("CARGO_PKG_VERSION")));
+        assert!(lines[1].starts_with("mmdc "));
+        assert_eq!(lines[2], format!("config {}", crate::config::current().render_options().config_hash()));
+    }
+
+    #[test]
+    fn applies_edits_bottom_up() {
+        let content = "# Doc\n```mermaid\npie\n```\ntext\n```mermaid\nflowchart TD\n```\n";
+        let edits = vec![
+            edit((1, 0), (4, 0), "IMAGE 0\n"),
+            edit((5, 0), (8, 0), "IMAGE 1\n"),
+        ];
+        assert_eq!(apply_text_edits(content, &edits), "# Doc\nIMAGE 0\ntext\nIMAGE 1\n");
+    }
+
+    #[test]
+    fn reads_settings_from_any_position() {
+        let args: Vec<String> = ["render", "--settings", "{\"theme\": \"dark\"}", "docs/"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let (settings, rest) = take_settings_arg(&args).unwrap();
+        assert_eq!(rest, vec!["render", "docs/"]);
+        assert_eq!(load_settings(&settings.unwrap()).unwrap().theme.as_deref(), Some("dark"));
+
+        assert!(take_settings_arg(&["check".to_string(), "--settings".to_string()]).is_err());
+    }
+
+    #[test]
+    fn loads_settings_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("settings.json");
+        fs::write(&file, r#"{ "mermaid": { "outputFormat": "png" } }"#).unwrap();
+        let settings = load_settings(&file.to_string_lossy()).unwrap();
+        assert_eq!(settings.output_format, config::OutputFormat::Png);
+        assert!(load_settings("{ not json").is_err());
+    }
+
+    #[test]
+    fn walks_directories_for_markdown_only() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("b.md"), "").unwrap();
+        fs::write(dir.path().join("a.markdown"), "").unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+        fs::create_dir_all(dir.path().join(".mermaid")).unwrap();
+        fs::write(dir.path().join(".mermaid").join("hidden.md"), "").unwrap();
+        fs::create_dir_all(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs").join("c.md"), "").unwrap();
+
+        let files = markdown_files(&[dir.path().to_path_buf()]).unwrap();
+        let names: Vec<String> = files
+            .iter()
+            .map(|f| f.strip_prefix(dir.path()).unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["a.markdown", "b.md", "docs/c.md"]);
+    }
+
+    #[test]
+    fn documents_include_mmd_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.md"), "").unwrap();
+        fs::write(dir.path().join("flow.mmd"), "").unwrap();
+        fs::create_dir_all(dir.path().join(".mermaid")).unwrap();
+        fs::write(dir.path().join(".mermaid").join("a_0_0123456789ab.mmd"), "").unwrap();
+
+        let files = document_files(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(files, vec![dir.path().join("a.md"), dir.path().join("flow.mmd")]);
+    }
+}
@@ -0,0 +1,446 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{env, sync::RwLock, time::Duration};
+
+use crate::cache::CacheLimits;
+use crate::directives::diagram_config;
+use crate::render::{ImageFormat, RenderOptions, DEFAULT_MERMAID_CONFIG};
+
+/// Environment variable that overrides the `renderer` setting (useful on CI machines).
+pub const RENDERER_ENV_VAR: &str = "MERMAID_RENDERER";
+
+/// Settings currently in effect; replaced once the client has sent its options.
+static CURRENT: Lazy<RwLock<Settings>> = Lazy::new(|| RwLock::new(Settings::default()));
+
+/// How rendered diagrams are presented.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PreviewMode {
+    /// Replace fences in the document with a source comment and an image link.
+    #[default]
+    Inline,
+    /// Leave the document untouched and render into `<doc>.preview.md` instead.
+    Companion,
+}
+
+/// Which image files rendering writes next to a document.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFormat {
+    #[default]
+    Svg,
+    Png,
+    /// Write both; the document links the SVG.
+    Both,
+}
+
+impl OutputFormat {
+    /// Formats to write, the linked one first.
+    pub fn formats(self) -> &'static [ImageFormat] {
+        match self {
+            OutputFormat::Svg => &[ImageFormat::Svg],
+            OutputFormat::Png => &[ImageFormat::Png],
+            OutputFormat::Both => &[ImageFormat::Svg, ImageFormat::Png],
+        }
+    }
+
+    /// Format the image link in the document points at.
+    pub fn linked(self) -> ImageFormat {
+        self.formats()[0]
+    }
+}
+
+/// Whether each diagram also gets a dark-theme rendition, and how the pair is linked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DarkMode {
+    /// Render once with the configured theme.
+    #[default]
+    Off,
+    /// Link both renditions from a `<picture>` element with a `prefers-color-scheme: dark` source.
+    Picture,
+    /// Link both renditions as GitHub's `#gh-light-mode-only` / `#gh-dark-mode-only` image pair.
+    Github,
+}
+
+/// Where rendered blocks keep their diagram source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SourceStorage {
+    /// A `.mmd` file in `.mermaid`, referenced by a `<!-- mermaid-source-file: -->` comment.
+    #[default]
+    File,
+    /// An HTML comment in the document.
+    Comment,
+    /// A collapsed `<details>` block in the document.
+    Details,
+}
+
+/// Server settings supplied by the client through `initializationOptions` and
+/// `workspace/didChangeConfiguration`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    /// Name of the rendering backend (see `render::renderer_from_name`).
+    pub renderer: Option<String>,
+    /// Whether rendering rewrites the document or writes a companion preview file.
+    pub preview_mode: PreviewMode,
+    /// Render new or changed fences whenever a document is saved.
+    pub render_on_save: bool,
+    /// Image format(s) written when rendering into the document.
+    pub output_format: OutputFormat,
+    /// Scale factor for PNG output; 2 renders at twice the resolution.
+    pub png_scale: Option<f64>,
+    /// Also render each diagram with the dark theme for readers using a dark color scheme.
+    pub dark_mode: DarkMode,
+    /// Embed images in the document as base64 `data:` URIs instead of linking files in `.mermaid`.
+    pub embed_images: bool,
+    /// Keep diagram source in a `.mmd` file or inside the document.
+    pub source_storage: SourceStorage,
+    /// Mermaid theme: `default`, `dark`, `forest`, `neutral` or `base`.
+    pub theme: Option<String>,
+    /// Background colour of rendered diagrams, e.g. `white` or `transparent`.
+    pub background: Option<String>,
+    /// Font for diagram text, passed to Mermaid as `fontFamily`.
+    pub font_family: Option<String>,
+    /// Overrides for Mermaid's `themeVariables`.
+    pub theme_variables: Option<Map<String, Value>>,
+    /// Per-diagram-type configuration keyed by Mermaid section, e.g. `{"flowchart": {"curve": "basis"}}`.
+    pub diagrams: Map<String, Value>,
+    /// Keep renders in one user-level cache shared by all workspaces instead of `.mermaid/.cache`.
+    pub shared_cache: bool,
+    /// Size limit of each cache directory, in megabytes.
+    pub cache_max_size_mb: Option<u64>,
+    /// Cached renders unused for longer than this are evicted.
+    pub cache_max_age_days: Option<u64>,
+    /// Set by [`Settings::dark_variant`]: the dark theme wins over a diagram's own `theme`.
+    #[serde(skip)]
+    dark_rendition: bool,
+}
+
+impl Settings {
+    /// Parse settings, skipping each field with an invalid value and describing why.
+    ///
+    /// Accepts the settings object itself or one nested under a `mermaid` key.
+    pub fn parse(value: Option<&Value>) -> (Self, Vec<String>) {
+        let Some(value) = value.filter(|value| !value.is_null()) else {
+            return (Settings::default(), Vec::new());
+        };
+        let value = value.get("mermaid").unwrap_or(value);
+        if let Ok(settings) = serde_json::from_value(value.clone()) {
+            return (settings, Vec::new());
+        }
+
+        let Some(fields) = value.as_object() else {
+            return (
+                Settings::default(),
+                vec![format!(
+                    "Ignoring Mermaid settings: expected an object, got {}",
+                    value
+                )],
+            );
+        };
+
+        // Keep every field that is valid on its own
+        let mut valid = Map::new();
+        let mut warnings = Vec::new();
+        for (key, field) in fields {
+            let single = Value::Object(Map::from_iter([(key.clone(), field.clone())]));
+            match serde_json::from_value::<Settings>(single) {
+                Ok(_) => {
+                    valid.insert(key.clone(), field.clone());
+                }
+                Err(e) => {
+                    warnings.push(format!("Ignoring invalid Mermaid setting '{}': {}", key, e))
+                }
+            }
+        }
+
+        let settings = serde_json::from_value(Value::Object(valid)).unwrap_or_default();
+        (settings, warnings)
+    }
+
+    /// The renderer to use, honouring the `MERMAID_RENDERER` override.
+    pub fn renderer_name(&self) -> String {
+        env::var(RENDERER_ENV_VAR)
+            .ok()
+            .filter(|name| !name.trim().is_empty())
+            .or_else(|| self.renderer.clone())
+            .unwrap_or_else(|| "mmdc".to_string())
+    }
+
+    /// Limits enforced when evicting from the render cache.
+    pub fn cache_limits(&self) -> CacheLimits {
+        let defaults = CacheLimits::default();
+        CacheLimits {
+            max_bytes: self
+                .cache_max_size_mb
+                .map(|mb| mb.saturating_mul(1024 * 1024))
+                .unwrap_or(defaults.max_bytes),
+            max_age: self
+                .cache_max_age_days
+                .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60)))
+                .unwrap_or(defaults.max_age),
+        }
+    }
+
+    /// These settings with the `dark` theme on a transparent background, for dark renditions.
+    pub fn dark_variant(&self) -> Settings {
+        Settings {
+            theme: Some("dark".to_string()),
+            background: Some("transparent".to_string()),
+            dark_rendition: true,
+            ..self.clone()
+        }
+    }
+
+    /// Render options for settings alone: the embedded Mermaid config with these settings on top.
+    pub fn render_options(&self) -> RenderOptions {
+        self.render_options_for("")
+    }
+
+    /// Effective render options for one diagram.
+    ///
+    /// Configuration is merged in increasing order of precedence:
+    /// 1. the embedded `mermaid-config.json`
+    /// 2. these settings (theme, fontFamily, background, themeVariables, diagrams)
+    /// 3. `config:` in the diagram's `---` frontmatter
+    /// 4. the diagram's `%%{init: ...}%%` directives
+    ///
+    /// For a dark rendition the `dark` theme is applied last, so it also replaces a theme
+    /// chosen by the diagram itself.
+    pub fn render_options_for(&self, code: &str) -> RenderOptions {
+        let mut config: Value = serde_json::from_str(DEFAULT_MERMAID_CONFIG)
+            .expect("embedded Mermaid config is valid JSON");
+
+        if let Some(theme) = &self.theme {
+            config["theme"] = Value::from(theme.as_str());
+        }
+        if let Some(font_family) = &self.font_family {
+            config["fontFamily"] = Value::from(font_family.as_str());
+        }
+        if let Some(background) = &self.background {
+            config["backgroundColor"] = Value::from(background.as_str());
+        }
+        if let Some(theme_variables) = &self.theme_variables {
+            merge_json(
+                &mut config["themeVariables"],
+                Value::Object(theme_variables.clone()),
+            );
+        }
+        for (section, overrides) in &self.diagrams {
+            merge_json(&mut config[section.as_str()], overrides.clone());
+        }
+
+        let diagram = diagram_config(code);
+        if diagram.is_object() {
+            merge_json(&mut config, diagram);
+        }
+        if self.dark_rendition {
+            config["theme"] = Value::from("dark");
+        }
+
+        let defaults = RenderOptions::default();
+        RenderOptions {
+            config: config.to_string(),
+            background: self.background.clone().unwrap_or(defaults.background),
+            scale: self
+                .png_scale
+                .filter(|scale| scale.is_finite() && *scale > 0.0)
+                .unwrap_or(defaults.scale),
+        }
+    }
+}
+
+/// Recursively merge `overlay` into `base`; objects are merged key by key, anything else replaces.
+pub fn merge_json(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge_json(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// A snapshot of the settings currently in effect.
+pub fn current() -> Settings {
+    match CURRENT.read() {
+        Ok(settings) => settings.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+/// Replace the settings currently in effect.
+pub fn update(settings: Settings) {
+    match CURRENT.write() {
+        Ok(mut current) => *current = settings,
+        Err(poisoned) => *poisoned.into_inner() = settings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_renderer_from_initialization_options() {
+        let options = json!({ "renderer": "mmdc" });
+        let settings = Settings::parse(Some(&options)).0;
+        assert_eq!(settings.renderer.as_deref(), Some("mmdc"));
+        assert_eq!(settings.preview_mode, PreviewMode::Inline);
+    }
+
+    #[test]
+    fn parses_companion_preview_mode() {
+        let options = json!({ "previewMode": "companion" });
+        assert_eq!(
+            Settings::parse(Some(&options)).0.preview_mode,
+            PreviewMode::Companion
+        );
+    }
+
+    #[test]
+    fn dark_variant_switches_theme_and_background() {
+        let settings = Settings::parse(Some(
+            &json!({ "darkMode": "picture", "fontFamily": "Inter" }),
+        ))
+        .0;
+        assert_eq!(settings.dark_mode, DarkMode::Picture);
+
+        let options = settings.dark_variant().render_options();
+        let config: Value = serde_json::from_str(&options.config).unwrap();
+        assert_eq!(config["theme"], "dark");
+        assert_eq!(config["fontFamily"], "Inter");
+        assert_eq!(options.background, "transparent");
+        assert_ne!(
+            options.config_hash(),
+            settings.render_options().config_hash()
+        );
+    }
+
+    #[test]
+    fn dark_variant_overrides_the_diagrams_own_theme() {
+        let settings = Settings::default();
+        let code = "%%{init: {'theme': 'forest'}}%%\nflowchart TD\n    A --> B";
+
+        let light: Value = serde_json::from_str(&settings.render_options_for(code).config).unwrap();
+        assert_eq!(light["theme"], "forest");
+        let dark: Value = serde_json::from_str(&settings.dark_variant().render_options_for(code).config).unwrap();
+        assert_eq!(dark["theme"], "dark");
+    }
+
+    #[test]
+    fn layers_theme_settings_over_embedded_config() {
+        let options = json!({
+            "theme": "dark",
+            "background": "transparent",
+            "themeVariables": { "primaryColor": "#333" },
+            "diagrams": { "flowchart": { "curve": "basis" } }
+        });
+        let render_options = Settings::parse(Some(&options)).0.render_options();
+        assert_eq!(render_options.background, "transparent");
+
+        let config: Value = serde_json::from_str(&render_options.config).unwrap();
+        assert_eq!(config["theme"], "dark");
+        assert_eq!(config["backgroundColor"], "transparent");
+        assert_eq!(config["fontFamily"], "Arial, sans-serif");
+        assert_eq!(config["themeVariables"]["primaryColor"], "#333");
+        // Embedded per-diagram defaults survive the merge
+        assert_eq!(
+            config["flowchart"],
+            json!({ "htmlLabels": false, "curve": "basis" })
+        );
+    }
+
+    #[test]
+    fn diagram_directives_override_settings() {
+        let settings = Settings::parse(Some(&json!({ "theme": "dark", "fontFamily": "Inter" }))).0;
+        let code = "---\nconfig:\n  theme: forest\n---\n%%{init: {'theme': 'neutral'}}%%\npie";
+
+        let config: Value =
+            serde_json::from_str(&settings.render_options_for(code).config).unwrap();
+        assert_eq!(config["theme"], "neutral");
+        assert_eq!(config["fontFamily"], "Inter");
+
+        let plain: Value =
+            serde_json::from_str(&settings.render_options_for("pie").config).unwrap();
+        assert_eq!(plain["theme"], "dark");
+    }
+
+    #[test]
+    fn parses_output_format_and_scale() {
+        let settings = Settings::parse(Some(&json!({ "outputFormat": "both", "pngScale": 2 }))).0;
+        assert_eq!(
+            settings.output_format.formats(),
+            &[ImageFormat::Svg, ImageFormat::Png]
+        );
+        assert_eq!(settings.output_format.linked(), ImageFormat::Svg);
+        assert_eq!(settings.render_options().scale, 2.0);
+
+        let png = Settings::parse(Some(&json!({ "outputFormat": "png", "pngScale": -1 }))).0;
+        assert_eq!(png.output_format.linked(), ImageFormat::Png);
+        assert_eq!(png.render_options().scale, 1.0);
+    }
+
+    #[test]
+    fn parses_cache_limits() {
+        let settings =
+            Settings::parse(Some(&json!({ "cacheMaxSizeMb": 5, "cacheMaxAgeDays": 2 }))).0;
+        let limits = settings.cache_limits();
+        assert_eq!(limits.max_bytes, 5 * 1024 * 1024);
+        assert_eq!(limits.max_age, Duration::from_secs(2 * 24 * 60 * 60));
+        assert_eq!(Settings::default().cache_limits(), CacheLimits::default());
+
+        // Absurdly large limits saturate instead of overflowing
+        let huge = Settings::parse(Some(
+            &json!({ "cacheMaxSizeMb": u64::MAX, "cacheMaxAgeDays": u64::MAX }),
+        ))
+        .0;
+        assert_eq!(huge.cache_limits().max_bytes, u64::MAX);
+        assert_eq!(huge.cache_limits().max_age, Duration::from_secs(u64::MAX));
+    }
+
+    #[test]
+    fn accepts_settings_nested_under_mermaid() {
+        let settings = json!({ "mermaid": { "theme": "forest" } });
+        assert_eq!(
+            Settings::parse(Some(&settings)).0.theme.as_deref(),
+            Some("forest")
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_options_fall_back_to_defaults() {
+        assert!(Settings::parse(None).0.renderer.is_none());
+        assert!(Settings::parse(Some(&Value::Null)).0.renderer.is_none());
+        assert!(Settings::parse(Some(&json!({ "renderer": 42 })))
+            .0
+            .renderer
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_fields_do_not_discard_valid_ones() {
+        let (settings, warnings) = Settings::parse(Some(&json!({
+            "theme": "forest",
+            "outputFormat": "gif",
+            "pngScale": "large"
+        })));
+        assert_eq!(settings.theme.as_deref(), Some("forest"));
+        assert_eq!(settings.output_format, OutputFormat::Svg);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("'outputFormat'")));
+
+        let (_, warnings) = Settings::parse(Some(&json!("dark")));
+        assert_eq!(warnings.len(), 1);
+        assert!(Settings::parse(Some(&json!({ "theme": "dark" })))
+            .1
+            .is_empty());
+    }
+}
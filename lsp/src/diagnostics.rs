@@ -0,0 +1,428 @@
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, warn};
+use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::config;
+use crate::render::{active_renderer, render_with, RenderOptions};
+
+/// Delay between the last edit and re-validating a document.
+pub const DIAGNOSTICS_DEBOUNCE: Duration = Duration::from_millis(500);
+
+const DIAGNOSTIC_SOURCE: &str = "mermaid";
+
+/// Maximum number of cached validation results kept in memory.
+const VALIDATION_CACHE_LIMIT: usize = 512;
+
+// Mermaid reports e.g. "Parse error on line 3:" or "Lexical error on line 2."
+static ERROR_LINE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)error on line (\d+)").expect("valid regex for mermaid error lines")
+});
+
+/// Validation results keyed by [`validation_key`], so unchanged diagrams are not
+/// re-rendered on every keystroke.
+static VALIDATION_CACHE: Lazy<Mutex<ValidationCache>> = Lazy::new(|| Mutex::new(ValidationCache::default()));
+
+/// Validation results, forgetting the oldest once [`VALIDATION_CACHE_LIMIT`] is reached.
+#[derive(Default)]
+struct ValidationCache {
+    results: HashMap<u64, Option<String>>,
+    /// Keys in insertion order, oldest first.
+    order: VecDeque<u64>,
+}
+
+impl ValidationCache {
+    fn get(&self, key: u64) -> Option<Option<String>> {
+        self.results.get(&key).cloned()
+    }
+
+    fn insert(&mut self, key: u64, result: Option<String>) {
+        if self.results.insert(key, result).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > VALIDATION_CACHE_LIMIT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.results.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Mermaid source found in a document, with its location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiagramSource {
+    pub code: String,
+    /// Document line of the opening fence (or of the first line in a `.mmd` file).
+    pub fence_line: usize,
+    /// Document line holding the first line of `code`.
+    pub first_code_line: usize,
+    /// Document line just past the code: the closing fence, or the line count of a `.mmd` file.
+    pub closing_line: usize,
+}
+
+/// Find every unrendered diagram in a Markdown document, or the whole `.mmd` file.
+pub fn find_diagram_sources(content: &str, is_mermaid_document: bool) -> Vec<DiagramSource> {
+    let lines: Vec<&str> = content.lines().collect();
+
+    if is_mermaid_document {
+        // A .mmd file may still carry a ```mermaid wrapper
+        let wrapped = lines
+            .first()
+            .map(|l| l.trim().starts_with("```mermaid"))
+            .unwrap_or(false);
+        let first_code_line = if wrapped { 1 } else { 0 };
+        let end = if wrapped {
+            lines
+                .iter()
+                .skip(1)
+                .position(|l| l.trim() == "```")
+                .map(|i| i + 1)
+                .unwrap_or(lines.len())
+        } else {
+            lines.len()
+        };
+
+        let code = lines
+            .get(first_code_line..end)
+            .map(|code_lines| code_lines.join("\n"))
+            .unwrap_or_default();
+        if code.trim().is_empty() {
+            return Vec::new();
+        }
+
+        return vec![DiagramSource {
+            code,
+            fence_line: 0,
+            first_code_line,
+            closing_line: end,
+        }];
+    }
+
+    let mut sources = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].trim_start().starts_with("```mermaid") {
+            let end = (i + 1..lines.len()).find(|&j| lines[j].trim_start().starts_with("```"));
+            let end = match end {
+                Some(end) => end,
+                None => break,
+            };
+
+            sources.push(DiagramSource {
+                code: lines[i + 1..end].join("\n"),
+                fence_line: i,
+                first_code_line: i + 1,
+                closing_line: end,
+            });
+            i = end + 1;
+        } else {
+            i += 1;
+        }
+    }
+
+    sources
+}
+
+/// Extract the 1-based line number from a Mermaid parser error message.
+pub fn parse_error_line(message: &str) -> Option<u32> {
+    ERROR_LINE_REGEX
+        .captures(message)
+        .and_then(|caps| caps[1].parse::<u32>().ok())
+        .filter(|line| *line > 0)
+}
+
+/// Reduce a renderer error (which may include a Node stack trace) to the useful part.
+pub fn summarize_error(message: &str) -> String {
+    let start = ERROR_LINE_REGEX
+        .find(message)
+        .map(|m| message[..m.start()].rfind('\n').map(|i| i + 1).unwrap_or(0))
+        .unwrap_or(0);
+
+    message[start..]
+        .lines()
+        .take_while(|line| !line.trim_start().starts_with("at "))
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Build the diagnostic for a diagram that failed to render.
+pub fn diagnostic_for_error(source: &DiagramSource, lines: &[&str], error: &str) -> Diagnostic {
+    let code_lines = source.code.lines().count().max(1);
+    let line = match parse_error_line(error) {
+        Some(error_line) => source.first_code_line + (error_line as usize - 1).min(code_lines - 1),
+        None => source.fence_line,
+    };
+    let line_len = lines.get(line).map(|l| l.len()).unwrap_or(0);
+
+    Diagnostic {
+        range: Range {
+            start: Position {
+                line: line as u32,
+                character: 0,
+            },
+            end: Position {
+                line: line as u32,
+                character: line_len as u32,
+            },
+        },
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some(DIAGNOSTIC_SOURCE.to_string()),
+        message: summarize_error(error),
+        ..Default::default()
+    }
+}
+
+/// Validate every diagram in the document and return diagnostics for the broken ones.
+pub fn diagnostics_for_document(content: &str, is_mermaid_document: bool) -> Vec<Diagnostic> {
+    let lines: Vec<&str> = content.lines().collect();
+
+    find_diagram_sources(content, is_mermaid_document)
+        .iter()
+        .filter(|source| !source.code.trim().is_empty())
+        .filter_map(|source| {
+            validate(&source.code).map(|error| diagnostic_for_error(source, &lines, &error))
+        })
+        .collect()
+}
+
+/// A document to validate in the background.
+struct ValidationJob {
+    uri: String,
+    content: String,
+    is_mermaid_document: bool,
+}
+
+/// Diagnostics computed for one version of a document.
+pub struct ValidationResult {
+    pub uri: String,
+    /// The content that was validated, to tell whether the result is still current.
+    pub content: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Validates documents on a background thread, so slow renders never hold up requests.
+pub struct Validator {
+    jobs: Sender<ValidationJob>,
+    results: Receiver<ValidationResult>,
+}
+
+impl Validator {
+    pub fn spawn() -> Self {
+        Self::spawn_with(diagnostics_for_document)
+    }
+
+    fn spawn_with(check: fn(&str, bool) -> Vec<Diagnostic>) -> Self {
+        let (jobs, job_receiver) = crossbeam_channel::unbounded::<ValidationJob>();
+        let (result_sender, results) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            while let Ok(job) = job_receiver.recv() {
+                // Only the latest queued version of each document is worth validating
+                let mut latest: Vec<ValidationJob> = Vec::new();
+                for job in std::iter::once(job).chain(job_receiver.try_iter()) {
+                    latest.retain(|queued| queued.uri != job.uri);
+                    latest.push(job);
+                }
+
+                for job in latest {
+                    let diagnostics = check(&job.content, job.is_mermaid_document);
+                    let result = ValidationResult {
+                        uri: job.uri,
+                        content: job.content,
+                        diagnostics,
+                    };
+                    if result_sender.send(result).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self { jobs, results }
+    }
+
+    /// Queue a document for validation; its result arrives on [`Validator::results`].
+    pub fn validate(&self, uri: &str, content: &str, is_mermaid_document: bool) {
+        let job = ValidationJob {
+            uri: uri.to_string(),
+            content: content.to_string(),
+            is_mermaid_document,
+        };
+        if self.jobs.send(job).is_err() {
+            warn!("Diagnostics thread has stopped; {} was not validated", uri);
+        }
+    }
+
+    pub fn results(&self) -> &Receiver<ValidationResult> {
+        &self.results
+    }
+}
+
+/// Whether a render error came from the Mermaid parser/renderer rather than the environment.
+fn is_diagram_error(message: &str) -> bool {
+    message.starts_with("mmdc error:") || message.starts_with("mermaid error:")
+}
+
+/// Cache key for a diagram's validation: its source, the options it renders with and the backend.
+fn validation_key(code: &str, options: &RenderOptions, renderer_name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code.hash(&mut hasher);
+    options.config_hash().hash(&mut hasher);
+    renderer_name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Render the diagram and return the error message if it fails.
+fn validate(code: &str) -> Option<String> {
+    let options = config::current().render_options_for(code);
+    let renderer = active_renderer();
+    let key = validation_key(code, &options, renderer.name());
+
+    if let Some(cached) = VALIDATION_CACHE.lock().ok().and_then(|cache| cache.get(key)) {
+        return cached;
+    }
+
+    debug!("Validating diagram {:x}", key);
+    let result = match render_with(renderer.as_ref(), code, &options) {
+        Ok(_) => None,
+        // Only errors reported by mermaid itself describe the diagram
+        Err(e) if is_diagram_error(&e.to_string()) => Some(e.to_string()),
+        Err(e) => {
+            // e.g. mmdc missing: not the diagram's fault, and worth retrying later
+            warn!("Skipping Mermaid validation: {}", e);
+            return None;
+        }
+    };
+
+    if let Ok(mut cache) = VALIDATION_CACHE.lock() {
+        cache.insert(key, result.clone());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MMDC_PARSE_ERROR: &str = "mmdc error: Error: Parse error on line 3:\n...--> B\n    B --\n----------^\nExpecting 'SEMI', 'NEWLINE', got 'EOF'\n    at Parser.parseError (file:///mermaid.js:1:2)\n    at Parser.parse (file:///mermaid.js:3:4)";
+
+    #[test]
+    fn finds_fences_in_markdown() {
+        let content = "# Doc\n\n```mermaid\nflowchart TD\n    A --> B\n```\n\n```rust\nfn main() {}\n```\n\n```mermaid\npie\n```\n";
+        let sources = find_diagram_sources(content, false);
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].fence_line, 2);
+        assert_eq!(sources[0].first_code_line, 3);
+        assert_eq!(sources[0].code, "flowchart TD\n    A --> B");
+        assert_eq!(sources[0].closing_line, 5);
+        assert_eq!(sources[1].fence_line, 11);
+        assert_eq!(sources[1].code, "pie");
+    }
+
+    #[test]
+    fn treats_whole_mmd_file_as_one_diagram() {
+        let sources = find_diagram_sources("flowchart TD\n    A --> B\n", true);
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].first_code_line, 0);
+
+        let wrapped = find_diagram_sources("```mermaid\nflowchart TD\n    A --> B\n```\n", true);
+        assert_eq!(wrapped[0].first_code_line, 1);
+        assert_eq!(wrapped[0].code, "flowchart TD\n    A --> B");
+    }
+
+    #[test]
+    fn parses_line_numbers_from_errors() {
+        assert_eq!(parse_error_line(MMDC_PARSE_ERROR), Some(3));
+        assert_eq!(parse_error_line("Lexical error on line 2. Unrecognized text."), Some(2));
+        assert_eq!(parse_error_line("mmdc not found in PATH"), None);
+    }
+
+    #[test]
+    fn summary_drops_stack_trace() {
+        let summary = summarize_error(MMDC_PARSE_ERROR);
+        assert!(summary.starts_with("mmdc error: Error: Parse error on line 3:"));
+        assert!(summary.contains("Expecting 'SEMI'"));
+        assert!(!summary.contains("Parser.parseError"));
+    }
+
+    #[test]
+    fn validates_in_the_background() {
+        fn slow_check(content: &str, _: bool) -> Vec<Diagnostic> {
+            thread::sleep(Duration::from_millis(50));
+            let source = DiagramSource {
+                code: content.to_string(),
+                fence_line: 0,
+                first_code_line: 0,
+                closing_line: 1,
+            };
+            vec![diagnostic_for_error(&source, &[content], "mermaid error: broken")]
+        }
+
+        let validator = Validator::spawn_with(slow_check);
+        validator.validate("file:///a.mmd", "first", true);
+        validator.validate("file:///b.mmd", "other", true);
+        validator.validate("file:///a.mmd", "second", true);
+
+        let mut validated = Vec::new();
+        while let Ok(result) = validator.results().recv_timeout(Duration::from_secs(5)) {
+            assert_eq!(result.diagnostics.len(), 1);
+            validated.push((result.uri, result.content));
+            if validated.iter().any(|(_, content)| content == "second") {
+                break;
+            }
+        }
+        // Superseded versions may be skipped, but the latest one is always validated last
+        assert_eq!(validated.last().unwrap(), &("file:///a.mmd".to_string(), "second".to_string()));
+        assert!(validated.iter().any(|(uri, _)| uri == "file:///b.mmd"));
+    }
+
+    #[test]
+    fn validation_key_covers_options_and_renderer() {
+        let options = RenderOptions::default();
+        let dark = config::Settings::parse(Some(&serde_json::json!({ "theme": "dark" }))).0.render_options();
+        let key = validation_key("pie", &options, "mmdc");
+        assert_eq!(key, validation_key("pie", &options, "mmdc"));
+        assert_ne!(key, validation_key("flowchart TD", &options, "mmdc"));
+        assert_ne!(key, validation_key("pie", &dark, "mmdc"));
+        assert_ne!(key, validation_key("pie", &options, "worker"));
+    }
+
+    #[test]
+    fn validation_cache_forgets_the_oldest_result() {
+        let mut cache = ValidationCache::default();
+        for key in 0..=VALIDATION_CACHE_LIMIT as u64 {
+            cache.insert(key, None);
+        }
+        assert_eq!(cache.results.len(), VALIDATION_CACHE_LIMIT);
+        assert_eq!(cache.get(0), None);
+        assert_eq!(cache.get(1), Some(None));
+        assert_eq!(cache.get(VALIDATION_CACHE_LIMIT as u64), Some(None));
+    }
+
+    #[test]
+    fn maps_error_line_into_fence() {
+        let content = "# Doc\n\n```mermaid\nflowchart TD\n    A --> B\n    B --\n```\n";
+        let lines: Vec<&str> = content.lines().collect();
+        let source = &find_diagram_sources(content, false)[0];
+
+        let diagnostic = diagnostic_for_error(source, &lines, MMDC_PARSE_ERROR);
+        assert_eq!(diagnostic.range.start.line, 5);
+        assert_eq!(diagnostic.range.end.character, "    B --".len() as u32);
+
+        // Errors without a line number point at the opening fence
+        let diagnostic = diagnostic_for_error(source, &lines, "mmdc error: No diagram type detected");
+        assert_eq!(diagnostic.range.start.line, 2);
+    }
+}
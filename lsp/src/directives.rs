@@ -0,0 +1,210 @@
+use log::warn;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::config::merge_json;
+
+// `%%{init: {...}}%%`, also spelled `initialize`; the body may span several lines
+static INIT_DIRECTIVE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?s)%%\{\s*init(?:ialize)?\s*:\s*(.*?)\s*\}%%")
+        .expect("valid regex for mermaid init directives")
+});
+
+// `accTitle: ...` and single-line `accDescr: ...`
+static ACC_STATEMENT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(accTitle|accDescr)\s*:\s*(.+)$").expect("valid regex for accessibility statements")
+});
+
+// A diagram's own title, e.g. `title Release plan` in gantt charts or `pie title Pets`
+static TITLE_STATEMENT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?:pie(?:\s+showData)?\s+)?title\s+(.+)$").expect("valid regex for title statements")
+});
+
+/// Accessible title and description declared by a diagram.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiagramLabel {
+    /// `accTitle`, the frontmatter `title`, or the diagram's own `title` statement.
+    pub title: Option<String>,
+    /// `accDescr`, on one line or as an `accDescr { ... }` block.
+    pub description: Option<String>,
+}
+
+impl DiagramLabel {
+    /// Alt text for the rendered image: the description, else the title.
+    pub fn alt_text(&self) -> &str {
+        self.description
+            .as_deref()
+            .or(self.title.as_deref())
+            .unwrap_or("Mermaid Diagram")
+    }
+}
+
+/// Read the accessible title and description of a diagram.
+pub fn diagram_label(code: &str) -> DiagramLabel {
+    let mut acc_title = None;
+    let mut description = None;
+    let mut title_statement = None;
+
+    let mut lines = diagram_body(code).lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if let Some(caps) = ACC_STATEMENT_REGEX.captures(line) {
+            let value = Some(caps[2].trim().to_string());
+            match &caps[1] {
+                "accTitle" => acc_title = acc_title.or(value),
+                _ => description = description.or(value),
+            }
+        } else if let Some(block) = line.strip_prefix("accDescr").map(str::trim_start).and_then(|l| l.strip_prefix('{')) {
+            // Multi-line description, closed by `}`
+            let mut text: Vec<&str> = vec![block.trim_end_matches('}')];
+            if !block.contains('}') {
+                for line in lines.by_ref() {
+                    text.push(line.trim_end_matches('}'));
+                    if line.contains('}') {
+                        break;
+                    }
+                }
+            }
+            let text = text.join(" ").split_whitespace().collect::<Vec<_>>().join(" ");
+            if description.is_none() && !text.is_empty() {
+                description = Some(text);
+            }
+        } else if let Some(caps) = TITLE_STATEMENT_REGEX.captures(line) {
+            if title_statement.is_none() {
+                title_statement = Some(caps[1].trim().trim_matches('"').to_string());
+            }
+        }
+    }
+
+    let frontmatter_title = frontmatter(code)
+        .and_then(|frontmatter| frontmatter.get("title").and_then(Value::as_str).map(str::to_string));
+
+    DiagramLabel {
+        title: acc_title.or(frontmatter_title).or(title_statement),
+        description,
+    }
+}
+
+/// Mermaid configuration declared inside a diagram, or `Value::Null` if there is none.
+///
+/// `config:` in the `---` frontmatter is applied first, then every `%%{init}%%`
+/// directive in source order, matching Mermaid's own precedence.
+pub fn diagram_config(code: &str) -> Value {
+    let mut config = Value::Null;
+
+    if let Some(frontmatter) = frontmatter_config(code) {
+        merge_json(&mut config, frontmatter);
+    }
+
+    for caps in INIT_DIRECTIVE_REGEX.captures_iter(code) {
+        // Directives are JSON-like but often use single quotes or bare keys, which YAML accepts
+        match serde_yaml::from_str::<Value>(&caps[1]) {
+            Ok(init @ Value::Object(_)) => merge_json(&mut config, init),
+            Ok(_) => warn!("Ignoring %%{{init}}%% directive that is not an object"),
+            Err(e) => warn!("Ignoring invalid %%{{init}}%% directive: {}", e),
+        }
+    }
+
+    config
+}
+
+/// The `config` key of a leading `---` YAML frontmatter block.
+fn frontmatter_config(code: &str) -> Option<Value> {
+    frontmatter(code)?.get("config").filter(|c| c.is_object()).cloned()
+}
+
+/// A leading `---` YAML frontmatter block.
+fn frontmatter(code: &str) -> Option<Value> {
+    let mut lines = code.trim_start().lines();
+    if lines.next()?.trim_end() != "---" {
+        return None;
+    }
+
+    let body: Vec<&str> = lines.take_while(|line| line.trim_end() != "---").collect();
+    match serde_yaml::from_str::<Value>(&body.join("\n")) {
+        Ok(frontmatter) => Some(frontmatter),
+        Err(e) => {
+            warn!("Ignoring invalid Mermaid frontmatter: {}", e);
+            None
+        }
+    }
+}
+
+/// The diagram without its frontmatter block.
+fn diagram_body(code: &str) -> &str {
+    let code = code.trim_start();
+    let mut offset = 0;
+    for (index, line) in code.split_inclusive('\n').enumerate() {
+        offset += line.len();
+        match (index, line.trim_end()) {
+            (0, "---") => {}
+            (0, _) => return code,
+            (_, "---") => return &code[offset..],
+            _ => {}
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_frontmatter_config() {
+        let code = "---\ntitle: Flow\nconfig:\n  theme: forest\n  flowchart:\n    curve: basis\n---\nflowchart TD\n    A --> B";
+        assert_eq!(
+            diagram_config(code),
+            json!({ "theme": "forest", "flowchart": { "curve": "basis" } })
+        );
+    }
+
+    #[test]
+    fn reads_init_directives_in_any_quoting() {
+        assert_eq!(
+            diagram_config("%%{init: {\"theme\": \"dark\"}}%%\nflowchart TD"),
+            json!({ "theme": "dark" })
+        );
+        assert_eq!(
+            diagram_config("%%{ initialize: { 'theme': 'neutral', themeVariables: { primaryColor: '#f00' } } }%%\npie"),
+            json!({ "theme": "neutral", "themeVariables": { "primaryColor": "#f00" } })
+        );
+    }
+
+    #[test]
+    fn init_directive_overrides_frontmatter() {
+        let code = "---\nconfig:\n  theme: forest\n  fontFamily: Inter\n---\n%%{init: {'theme': 'dark'}}%%\nflowchart TD";
+        assert_eq!(diagram_config(code), json!({ "theme": "dark", "fontFamily": "Inter" }));
+    }
+
+    #[test]
+    fn reads_accessibility_statements() {
+        let code = "flowchart TD\n    accTitle: Login flow\n    accDescr: User signs in, then sees the dashboard\n    A --> B";
+        let label = diagram_label(code);
+        assert_eq!(label.title.as_deref(), Some("Login flow"));
+        assert_eq!(label.alt_text(), "User signs in, then sees the dashboard");
+
+        let code = "sequenceDiagram\n    accDescr {\n        Alice greets Bob\n        and Bob replies\n    }\n    Alice->>Bob: Hi";
+        assert_eq!(diagram_label(code).alt_text(), "Alice greets Bob and Bob replies");
+    }
+
+    #[test]
+    fn falls_back_to_frontmatter_and_title_statements() {
+        let code = "---\ntitle: Checkout\n---\nflowchart LR\n    A --> B";
+        assert_eq!(diagram_label(code).alt_text(), "Checkout");
+
+        assert_eq!(diagram_label("pie title Pets adopted\n    \"Dogs\" : 386").alt_text(), "Pets adopted");
+        assert_eq!(diagram_label("gantt\n    title Release plan\n    section A").alt_text(), "Release plan");
+
+        let plain = diagram_label("flowchart TD\n    A --> B");
+        assert_eq!(plain, DiagramLabel::default());
+        assert_eq!(plain.alt_text(), "Mermaid Diagram");
+    }
+
+    #[test]
+    fn plain_diagrams_have_no_config() {
+        assert_eq!(diagram_config("flowchart TD\n    A --> B"), Value::Null);
+        assert_eq!(diagram_config("%%{init: not valid: yaml: [}%%\npie"), Value::Null);
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use lopdf::{Document, Object, ObjectId};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::render::ImageFormat;
+use crate::{media_dir_for, render_format_to_cache, unrendered_diagram_blocks, RenderFailure};
+
+/// Directory inside `.mermaid` that exported PDFs are written to.
+pub const EXPORT_DIR: &str = "export";
+
+/// Outcome of exporting a document's diagrams.
+#[derive(Debug, Default)]
+pub struct PdfExport {
+    pub files: Vec<PathBuf>,
+    pub failures: Vec<RenderFailure>,
+}
+
+/// Render every fenced diagram in a document to PDF under `.mermaid/export/`.
+///
+/// Each diagram becomes `<doc>_<diagram>.pdf`, or with `combined` all of them are
+/// merged into a single `<doc>.pdf` with one page per diagram, in document order.
+pub fn export_pdf(uri: &str, content: &str, combined: bool) -> Result<PdfExport> {
+    let url = lsp_types::Url::parse(uri).map_err(|e| anyhow!("Invalid URI: {}", e))?;
+    let path = url
+        .to_file_path()
+        .map_err(|_| anyhow!("Failed to convert URI to file path"))?;
+    let stem = path
+        .file_stem()
+        .ok_or_else(|| anyhow!("Document has no file name: {:?}", path))?
+        .to_string_lossy()
+        .to_string();
+
+    let blocks = unrendered_diagram_blocks(uri, content);
+    if blocks.is_empty() {
+        return Err(anyhow!("No Mermaid diagrams to export"));
+    }
+
+    let media_dir = media_dir_for(&path)?;
+    let export_dir = media_dir.join(EXPORT_DIR);
+    fs::create_dir_all(&export_dir)
+        .map_err(|e| anyhow!("Failed to create export directory: {}", e))?;
+
+    let mut export = PdfExport::default();
+    let mut pdfs = Vec::new();
+    for block in &blocks {
+        match render_format_to_cache(&block.code, &media_dir, ImageFormat::Pdf) {
+            Ok((_, pdf)) => pdfs.push((block.name.clone(), pdf)),
+            Err(e) => export.failures.push(RenderFailure {
+                line: block.start.line as usize + 1,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    if combined {
+        if !pdfs.is_empty() {
+            let merged = merge_pdfs(pdfs.iter().map(|(_, pdf)| pdf.as_slice()))?;
+            export.files.push(write_export(&export_dir, &format!("{}.pdf", stem), &merged)?);
+        }
+    } else {
+        for (name, pdf) in &pdfs {
+            export.files.push(write_export(&export_dir, &format!("{}_{}.pdf", stem, name), pdf)?);
+        }
+    }
+
+    info!("Exported {} PDF file(s) for {}", export.files.len(), uri);
+    Ok(export)
+}
+
+fn write_export(export_dir: &Path, file_name: &str, pdf: &[u8]) -> Result<PathBuf> {
+    let path = export_dir.join(file_name);
+    fs::write(&path, pdf).map_err(|e| anyhow!("Failed to write {:?}: {}", path, e))?;
+    Ok(path)
+}
+
+/// Concatenate PDFs into one document, keeping their page order.
+pub fn merge_pdfs<'a>(pdfs: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<u8>> {
+    let mut max_id = 1;
+    let mut pages: Vec<(ObjectId, Object)> = Vec::new();
+    let mut objects = Vec::new();
+
+    for pdf in pdfs {
+        let mut doc = Document::load_mem(pdf).map_err(|e| anyhow!("Failed to read PDF: {}", e))?;
+        // Give every document its own id range so objects don't collide
+        doc.renumber_objects_with(max_id);
+        max_id = doc.max_id + 1;
+
+        for (_, page_id) in doc.get_pages() {
+            let page = doc
+                .get_object(page_id)
+                .map_err(|e| anyhow!("Failed to read PDF page: {}", e))?
+                .to_owned();
+            pages.push((page_id, page));
+        }
+        objects.extend(doc.objects);
+    }
+
+    let mut merged = Document::with_version("1.5");
+    let mut catalog: Option<(ObjectId, Object)> = None;
+    let mut pages_root: Option<(ObjectId, Object)> = None;
+
+    for (object_id, object) in objects {
+        match object.type_name().unwrap_or("") {
+            // Keep the first catalog and page tree; every page is re-parented onto them
+            "Catalog" => {
+                if catalog.is_none() {
+                    catalog = Some((object_id, object));
+                }
+            }
+            "Pages" => {
+                if pages_root.is_none() {
+                    pages_root = Some((object_id, object));
+                }
+            }
+            "Page" | "Outlines" | "Outline" => {}
+            _ => {
+                merged.objects.insert(object_id, object);
+            }
+        }
+    }
+
+    let (catalog_id, catalog) = catalog.ok_or_else(|| anyhow!("PDF has no catalog"))?;
+    let (pages_id, pages_root) = pages_root.ok_or_else(|| anyhow!("PDF has no page tree"))?;
+
+    for (page_id, page) in &pages {
+        let mut page = page
+            .as_dict()
+            .map_err(|e| anyhow!("Invalid PDF page: {}", e))?
+            .clone();
+        page.set("Parent", pages_id);
+        merged.objects.insert(*page_id, Object::Dictionary(page));
+    }
+
+    let mut pages_root = pages_root
+        .as_dict()
+        .map_err(|e| anyhow!("Invalid PDF page tree: {}", e))?
+        .clone();
+    pages_root.set("Count", pages.len() as u32);
+    pages_root.set(
+        "Kids",
+        pages
+            .iter()
+            .map(|(page_id, _)| Object::Reference(*page_id))
+            .collect::<Vec<_>>(),
+    );
+    merged.objects.insert(pages_id, Object::Dictionary(pages_root));
+
+    let mut catalog = catalog
+        .as_dict()
+        .map_err(|e| anyhow!("Invalid PDF catalog: {}", e))?
+        .clone();
+    catalog.set("Pages", pages_id);
+    catalog.remove(b"Outlines");
+    merged.objects.insert(catalog_id, Object::Dictionary(catalog));
+
+    merged.trailer.set("Root", catalog_id);
+    merged.max_id = merged.objects.keys().map(|(id, _)| *id).max().unwrap_or(0);
+    merged.renumber_objects();
+
+    let mut output = Vec::new();
+    merged
+        .save_to(&mut output)
+        .map_err(|e| anyhow!("Failed to write merged PDF: {}", e))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    fn one_page_pdf(width: i64) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), width.into(), 100.into()],
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page_id.into()],
+                "Count" => 1,
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let mut output = Vec::new();
+        doc.save_to(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn merges_pdfs_into_pages_in_order() {
+        let first = one_page_pdf(200);
+        let second = one_page_pdf(300);
+        let merged = merge_pdfs([first.as_slice(), second.as_slice()]).unwrap();
+
+        let doc = Document::load_mem(&merged).unwrap();
+        let widths: Vec<i64> = doc
+            .get_pages()
+            .values()
+            .map(|page_id| {
+                let page = doc.get_dictionary(*page_id).unwrap();
+                page.get(b"MediaBox").unwrap().as_array().unwrap()[2].as_i64().unwrap()
+            })
+            .collect();
+        assert_eq!(widths, vec![200, 300]);
+    }
+
+    #[test]
+    fn rejects_invalid_pdfs() {
+        assert!(merge_pdfs([b"<svg/>".as_slice()]).is_err());
+    }
+}
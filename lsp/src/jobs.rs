@@ -0,0 +1,82 @@
+use crossbeam_channel::{Receiver, Sender};
+use log::warn;
+use std::thread;
+
+/// Runs slow work (renders after a save, sidecar syncs) on a background thread, so it
+/// never holds up requests on the main loop.
+///
+/// Jobs run one at a time in the order they were queued. Of the jobs that queue up while
+/// the thread is busy, only the latest with each key runs, so repeated saves coalesce.
+pub struct Jobs<J, R> {
+    jobs: Sender<J>,
+    results: Receiver<R>,
+}
+
+impl<J: Send + 'static, R: Send + 'static> Jobs<J, R> {
+    pub fn spawn<K: PartialEq + 'static>(key: fn(&J) -> K, run: impl Fn(J) -> R + Send + 'static) -> Self {
+        let (jobs, job_receiver) = crossbeam_channel::unbounded::<J>();
+        let (result_sender, results) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            while let Ok(job) = job_receiver.recv() {
+                let mut latest: Vec<J> = Vec::new();
+                for job in std::iter::once(job).chain(job_receiver.try_iter()) {
+                    latest.retain(|queued| key(queued) != key(&job));
+                    latest.push(job);
+                }
+
+                for job in latest {
+                    if result_sender.send(run(job)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self { jobs, results }
+    }
+
+    /// Queue a job; its result arrives on [`Jobs::results`].
+    pub fn queue(&self, job: J) {
+        if self.jobs.send(job).is_err() {
+            warn!("Background thread has stopped; job dropped");
+        }
+    }
+
+    pub fn results(&self) -> &Receiver<R> {
+        &self.results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_the_latest_job_for_each_key() {
+        let jobs = Jobs::spawn(
+            |job: &(&str, u32)| job.0,
+            |job| {
+                thread::sleep(Duration::from_millis(50));
+                job
+            },
+        );
+        jobs.queue(("a", 1));
+        jobs.queue(("a", 2));
+        jobs.queue(("b", 1));
+        jobs.queue(("a", 3));
+
+        let mut done = Vec::new();
+        while let Ok(job) = jobs.results().recv_timeout(Duration::from_secs(5)) {
+            done.push(job);
+            if job == ("a", 3) {
+                break;
+            }
+        }
+        // The first job may start before the rest are queued; the others coalesce
+        assert!(done.len() <= 3, "ran {:?}", done);
+        assert!(!done.contains(&("a", 2)));
+        assert!(done.contains(&("b", 1)));
+    }
+}
@@ -1,33 +1,72 @@
 use anyhow::{anyhow, Result};
+use base64::Engine;
+use crossbeam_channel::RecvTimeoutError;
 use log::{debug, error, info, warn};
 use lsp_server::{Connection, Message, Request, RequestId, Response, ResponseError};
 use lsp_types::*;
 use serde_json::json;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    path::Path,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::{SystemTime, UNIX_EPOCH},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Instant,
 };
 use url::Url;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::hash::Hash;
+
+mod cache;
+mod check;
+mod cli;
+mod config;
+mod diagnostics;
+mod directives;
+mod export;
+mod jobs;
+mod preview;
 mod render;
-
-use crate::render::render_mermaid;
+mod sidecar;
+mod worker;
+
+use crate::cli::apply_text_edits;
+use crate::config::{DarkMode, PreviewMode, Settings, SourceStorage};
+use crate::diagnostics::{ValidationResult, Validator, DIAGNOSTICS_DEBOUNCE};
+use crate::directives::{diagram_label, DiagramLabel};
+use crate::jobs::Jobs;
+use crate::render::{
+    active_renderer, render_mermaid_format_with, renderer_from_name, set_renderer,
+    ImageFormat, RenderOptions,
+};
 
 // Constants to avoid magic strings
 const MERMAID_SOURCE_COMMENT_PREFIX: &str = "<!-- mermaid-source-file:";
 const MERMAID_SOURCE_COMMENT_SUFFIX: &str = "-->";
+const MERMAID_SOURCE_HASH_PREFIX: &str = "<!-- mermaid-source-hash:";
+const PNG_SOURCE_HASH_KEYWORD: &str = "mermaid-source-hash";
+const MERMAID_INLINE_SOURCE_START: &str = "<!-- mermaid-source";
+const MERMAID_SOURCE_DETAILS_START: &str = "<details><summary>Mermaid source</summary>";
+const MERMAID_SOURCE_DETAILS_END: &str = "</details>";
+const MERMAID_SOURCE_DETAILS_FENCE: &str = "```mmd";
 const MERMAID_MEDIA_DIR: &str = ".mermaid";
 const MERMAID_CACHE_DIR: &str = ".cache";
 const MERMAID_FILE_EXTENSION: &str = ".mmd";
 const MERMAID_FENCE_START: &str = "```mermaid";
 const MERMAID_FENCE_END: &str = "```";
 
-static SVG_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// Fence info attribute naming a diagram, e.g. ```mermaid id=login-flow or ```mermaid {#login-flow}
+static DIAGRAM_ID_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:\bid=|#)([A-Za-z0-9_-]+)").expect("valid regex for diagram ids")
+});
+
+/// Action confirming that the cache shared by every workspace should be cleared.
+const CLEAR_SHARED_CACHE_ACTION: &str = "Clear Shared Cache";
+
+/// Number of hex digits of the content hash kept in output filenames.
+const CONTENT_HASH_LENGTH: usize = 12;
 
 /// Send an error notification to the LSP client
 fn send_error_notification(connection: &Connection, message: &str) {
@@ -44,8 +83,22 @@ fn send_error_notification(connection: &Connection, message: &str) {
     }
 }
 
+/// Send an informational notification to the LSP client
+fn send_info_notification(connection: &Connection, message: &str) {
+    let notification = lsp_server::Notification {
+        method: "window/showMessage".to_string(),
+        params: json!({
+            "type": MessageType::INFO,
+            "message": format!("Mermaid: {}", message)
+        }),
+    };
+
+    if let Err(e) = connection.sender.send(Message::Notification(notification)) {
+        error!("Failed to send info notification: {}", e);
+    }
+}
+
 /// Send a warning notification to the LSP client
-#[allow(dead_code)]
 fn send_warning_notification(connection: &Connection, message: &str) {
     let notification = lsp_server::Notification {
         method: "window/showMessage".to_string(),
@@ -145,6 +198,16 @@ fn find_most_recent_source_file(missing_path: &Path, _uri: &str) -> Option<Strin
 }
 
 fn main() -> Result<()> {
+    // Any arguments select a command-line mode instead of the language server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+            .init();
+        let code = cli::run(&args)?;
+        active_renderer().shutdown();
+        std::process::exit(code);
+    }
+
     // Initialize logging to a file so we can actually see what's happening
     let log_file = Path::new("/tmp/mermaid-lsp.log");
 
@@ -180,16 +243,28 @@ fn main() -> Result<()> {
 
     // Initialize LSP
     let server_capabilities = ServerCapabilities {
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(
-            TextDocumentSyncKind::INCREMENTAL,
+        text_document_sync: Some(TextDocumentSyncCapability::Options(
+            TextDocumentSyncOptions {
+                open_close: Some(true),
+                change: Some(TextDocumentSyncKind::INCREMENTAL),
+                save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                    include_text: Some(false),
+                })),
+                ..Default::default()
+            },
         )),
         code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
         execute_command_provider: Some(ExecuteCommandOptions {
             commands: vec![
                 "mermaid.renderAllLightweight".to_string(),
                 "mermaid.renderSingle".to_string(),
                 "mermaid.editAllSources".to_string(),
                 "mermaid.editSingleSource".to_string(),
+                "mermaid.openPreview".to_string(),
+                "mermaid.clearCache".to_string(),
+                "mermaid.pruneCache".to_string(),
+                "mermaid.exportPdf".to_string(),
             ],
             work_done_progress_options: WorkDoneProgressOptions {
                 work_done_progress: None,
@@ -208,18 +283,59 @@ fn main() -> Result<()> {
         .unwrap_or("<none>");
     info!("Mermaid LSP initialized for workspace: {}", root_uri);
 
+    // Settings (renderer, theme, ...) come from initializationOptions
+    apply_client_settings(&connection, initialize_params.get("initializationOptions"));
+
+    // Requests sent to the client, awaiting their responses
+    let mut pending_requests = PendingRequests::default();
+
+    // Re-render images when their .mmd sidecar is edited
+    if let Err(e) = sidecar::register_watcher(&connection, &mut pending_requests, &initialize_params) {
+        warn!("Failed to register .mmd file watcher: {}", e);
+    }
+
     // Store document content
     let mut documents: HashMap<String, String> = HashMap::new();
 
+    // Documents waiting for (debounced) diagnostics, with the time they become due
+    let mut pending_diagnostics: HashMap<String, Instant> = HashMap::new();
+
+    // Diagrams are validated off the main loop; results come back over a channel
+    let validator = Validator::spawn();
+
+    // Work started by saves also runs off the main loop
+    let save_jobs: SaveJobs = Jobs::spawn(SaveJob::key, run_save_job);
+
     // Main message loop
     loop {
-        match connection.receiver.recv() {
+        let debounce = match pending_diagnostics.values().min() {
+            Some(deadline) => crossbeam_channel::at(*deadline),
+            None => crossbeam_channel::never(),
+        };
+        let received = crossbeam_channel::select! {
+            recv(connection.receiver) -> msg => msg.map_err(|_| RecvTimeoutError::Disconnected),
+            recv(validator.results()) -> result => {
+                if let Ok(result) = result {
+                    publish_validation_result(&connection, &documents, result);
+                }
+                continue;
+            }
+            recv(save_jobs.results()) -> outcome => {
+                if let Ok(outcome) = outcome {
+                    handle_save_outcome(&connection, &mut pending_requests, &documents, outcome);
+                }
+                continue;
+            }
+            recv(debounce) -> _ => Err(RecvTimeoutError::Timeout),
+        };
+
+        match received {
             Ok(msg) => {
                 match msg {
                     Message::Request(req) => {
                         debug!("Received request: {}", req.method);
                         let req_id = req.id.clone();
-                        match handle_request(&connection, req, &mut documents) {
+                        match handle_request(&connection, req, &mut documents, &mut pending_requests) {
                             Ok(()) => {
                                 debug!("Request handled successfully");
                             }
@@ -239,25 +355,36 @@ fn main() -> Result<()> {
                             }
                         }
                     }
-                    Message::Response(_) => {
-                        // Handle responses if needed
+                    Message::Response(response) => {
+                        handle_response(&connection, response, &mut pending_requests, &documents);
                     }
                     Message::Notification(notif) => {
                         debug!("Received notification: {}", notif.method);
-                        if let Err(e) = handle_notification(notif, &connection, &mut documents) {
+                        if let Err(e) = handle_notification(
+                            notif,
+                            &connection,
+                            &mut documents,
+                            &mut pending_diagnostics,
+                            &save_jobs,
+                        ) {
                             error!("Error handling notification: {}", e);
                         }
                     }
                 }
             }
-            Err(err) => {
-                error!("LSP connection error: {}", err);
+            Err(RecvTimeoutError::Timeout) => {
+                validate_due_documents(&validator, &documents, &mut pending_diagnostics);
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("LSP connection closed");
                 break;
             }
         }
     }
 
     info!("LSP shutting down...");
+    active_renderer().shutdown();
+    cache::flush_accesses();
     io_threads.join()?;
     Ok(())
 }
@@ -266,6 +393,7 @@ fn handle_request(
     connection: &Connection,
     req: Request,
     documents: &mut HashMap<String, String>,
+    pending_requests: &mut PendingRequests,
 ) -> Result<()> {
     debug!("Received request: {}", req.method);
     match req.method.as_str() {
@@ -293,12 +421,26 @@ fn handle_request(
             connection.sender.send(Message::Response(response))?;
             info!("=== CODE ACTION RESPONSE SENT ===");
         }
+        "textDocument/hover" => {
+            let params: HoverParams = serde_json::from_value(req.params)
+                .map_err(|e| anyhow::anyhow!("Invalid hover params: {}", e))?;
+
+            let hover = get_hover(&params, documents)?;
+
+            let response = Response {
+                id: req.id,
+                result: Some(json!(hover)),
+                error: None,
+            };
+
+            connection.sender.send(Message::Response(response))?;
+        }
         "workspace/executeCommand" => {
             info!("Processing execute command request...");
             let params: ExecuteCommandParams = serde_json::from_value(req.params)
                 .map_err(|e| anyhow::anyhow!("Invalid executeCommand params: {}", e))?;
 
-            execute_command(&params, documents, connection)?;
+            execute_command(&params, documents, connection, pending_requests)?;
 
             // Return empty response - the edit is applied via workspace/applyEdit
             let response = Response {
@@ -311,6 +453,8 @@ fn handle_request(
         }
         "shutdown" => {
             info!("LSP received shutdown request");
+            active_renderer().shutdown();
+            cache::flush_accesses();
             let response = Response {
                 id: req.id,
                 result: Some(json!(null)),
@@ -338,8 +482,10 @@ fn handle_request(
 
 fn handle_notification(
     notif: lsp_server::Notification,
-    _connection: &Connection,
+    connection: &Connection,
     documents: &mut HashMap<String, String>,
+    pending_diagnostics: &mut HashMap<String, Instant>,
+    save_jobs: &SaveJobs,
 ) -> Result<()> {
     debug!("Received notification: {}", notif.method);
     // Handle notifications directly
@@ -350,7 +496,8 @@ fn handle_notification(
 
             let uri = params.text_document.uri.to_string();
             let text = params.text_document.text;
-            documents.insert(uri, text);
+            documents.insert(uri.clone(), text);
+            pending_diagnostics.insert(uri, Instant::now());
         }
         "textDocument/didChange" => {
             let params: DidChangeTextDocumentParams = serde_json::from_value(notif.params)
@@ -372,6 +519,32 @@ fn handle_notification(
                         }
                     }
                 }
+                pending_diagnostics.insert(uri, Instant::now() + DIAGNOSTICS_DEBOUNCE);
+            }
+        }
+        "textDocument/didSave" => {
+            let params: DidSaveTextDocumentParams = serde_json::from_value(notif.params)
+                .map_err(|e| anyhow::anyhow!("Invalid didSave params: {}", e))?;
+
+            let uri = params.text_document.uri.to_string();
+            if let Some(content) = documents.get(&uri) {
+                refresh_preview_on_save(save_jobs, &params.text_document.uri, content);
+                render_on_save(save_jobs, &uri, content);
+            }
+        }
+        "workspace/didChangeConfiguration" => {
+            let params: DidChangeConfigurationParams = serde_json::from_value(notif.params)
+                .map_err(|e| anyhow::anyhow!("Invalid didChangeConfiguration params: {}", e))?;
+
+            info!("Mermaid settings changed");
+            apply_client_settings(connection, Some(&params.settings));
+        }
+        "workspace/didChangeWatchedFiles" => {
+            let params: DidChangeWatchedFilesParams = serde_json::from_value(notif.params)
+                .map_err(|e| anyhow::anyhow!("Invalid didChangeWatchedFiles params: {}", e))?;
+
+            for source in sidecar::changed_sidecars(&params.changes) {
+                save_jobs.queue(SaveJob::Sidecar { source });
             }
         }
         "textDocument/didClose" => {
@@ -380,6 +553,8 @@ fn handle_notification(
 
             let uri = params.text_document.uri.to_string();
             documents.remove(&uri);
+            pending_diagnostics.remove(&uri);
+            publish_diagnostics(connection, &params.text_document.uri, Vec::new());
         }
         _ => {}
     }
@@ -387,6 +562,491 @@ fn handle_notification(
     Ok(())
 }
 
+/// Parse settings sent by the client and make them current, warning about invalid fields.
+fn apply_client_settings(connection: &Connection, value: Option<&serde_json::Value>) {
+    let (settings, warnings) = Settings::parse(value);
+    for warning in warnings {
+        warn!("{}", warning);
+        send_warning_notification(connection, &warning);
+    }
+    apply_settings(connection, settings);
+}
+
+/// Make `settings` current, switching the rendering backend (or MERMAID_RENDERER) if it changed.
+fn apply_settings(connection: &Connection, settings: Settings) {
+    let renderer_name = settings.renderer_name();
+    if renderer_name != active_renderer().name() {
+        match renderer_from_name(&renderer_name) {
+            Ok(renderer) => {
+                info!("Using '{}' renderer", renderer.name());
+                active_renderer().shutdown();
+                set_renderer(renderer);
+            }
+            Err(e) => {
+                error!("{}; keeping '{}' renderer", e, active_renderer().name());
+                send_error_notification(connection, &e.to_string());
+            }
+        }
+    }
+    config::update(settings);
+}
+
+/// Work started by a save (of a document or a sidecar `.mmd`), run off the main loop.
+enum SaveJob {
+    /// Rewrite the companion preview of a document.
+    Preview {
+        path: PathBuf,
+        content: String,
+        is_mermaid: bool,
+    },
+    /// Render a document's new or changed fences (`renderOnSave`).
+    Render { uri: String, content: String },
+    /// Re-render the images of an edited `.mermaid/*.mmd` sidecar.
+    Sidecar { source: PathBuf },
+}
+
+impl SaveJob {
+    /// Jobs with the same key supersede each other while queued.
+    fn key(&self) -> (&'static str, String) {
+        match self {
+            SaveJob::Preview { path, .. } => ("preview", path.to_string_lossy().to_string()),
+            SaveJob::Render { uri, .. } => ("render", uri.clone()),
+            SaveJob::Sidecar { source } => ("sidecar", source.to_string_lossy().to_string()),
+        }
+    }
+}
+
+/// Diagrams rendered after a save, with the edit linking them.
+struct RenderedOnSave {
+    uri: String,
+    /// The content that was rendered, to tell whether the edit still applies.
+    content: String,
+    changes: HashMap<Url, Vec<TextEdit>>,
+    failures: Vec<RenderFailure>,
+}
+
+/// What a [`SaveJob`] reports back to the main loop.
+enum SaveOutcome {
+    Done,
+    Rendered(RenderedOnSave),
+    /// A failure to show the user.
+    Failed(String),
+}
+
+type SaveJobs = Jobs<SaveJob, SaveOutcome>;
+
+fn run_save_job(job: SaveJob) -> SaveOutcome {
+    match job {
+        SaveJob::Preview { path, content, is_mermaid } => match preview::write_preview(&path, &content, is_mermaid) {
+            Ok(_) => SaveOutcome::Done,
+            Err(e) => {
+                error!("Failed to refresh Mermaid preview: {}", e);
+                SaveOutcome::Failed(format!("Failed to refresh preview: {}", e))
+            }
+        },
+        SaveJob::Render { uri, content } => {
+            let (changes, failures) = render_all_diagrams(&uri, &content, WriteMode::Write);
+            SaveOutcome::Rendered(RenderedOnSave {
+                uri,
+                content,
+                changes,
+                failures,
+            })
+        }
+        SaveJob::Sidecar { source } => match sidecar::sync_sidecar(&source) {
+            Ok(_) => SaveOutcome::Done,
+            Err(e) => {
+                warn!("Failed to re-render {:?}: {}", source, e);
+                SaveOutcome::Failed(format!("Failed to re-render {}: {}", source.display(), e))
+            }
+        },
+    }
+}
+
+fn handle_save_outcome(
+    connection: &Connection,
+    pending_requests: &mut PendingRequests,
+    documents: &HashMap<String, String>,
+    outcome: SaveOutcome,
+) {
+    match outcome {
+        SaveOutcome::Done => {}
+        SaveOutcome::Rendered(rendered) => {
+            if let Err(e) = apply_rendered_on_save(connection, pending_requests, documents, rendered) {
+                error!("Failed to apply diagrams rendered on save: {}", e);
+            }
+        }
+        SaveOutcome::Failed(message) => send_error_notification(connection, &message),
+    }
+}
+
+/// Keep the companion preview in sync with a saved document.
+///
+/// In companion mode every save writes the preview; otherwise an existing preview
+/// (created through "Open Mermaid Preview") is refreshed. The preview is written in the background.
+fn refresh_preview_on_save(save_jobs: &SaveJobs, url: &Url, content: &str) {
+    let Ok(path) = url.to_file_path() else {
+        return;
+    };
+    let Some(target) = preview::preview_path(&path) else {
+        return;
+    };
+
+    let companion_mode = config::current().preview_mode == PreviewMode::Companion;
+    let has_diagrams = is_mermaid_document(url.as_str()) || count_mermaid_blocks(content) > 0;
+    let should_write = target.exists() || (companion_mode && has_diagrams);
+    if !should_write {
+        return;
+    }
+
+    save_jobs.queue(SaveJob::Preview {
+        path,
+        content: content.to_string(),
+        is_mermaid: is_mermaid_document(url.as_str()),
+    });
+}
+
+/// Stale diagram files to remove once a render edit has been applied.
+struct PendingCleanup {
+    uri: String,
+    /// The document as it reads once the edit is applied.
+    content: String,
+}
+
+impl PendingCleanup {
+    /// Cleanup for `uri` once `changes` have been applied to its current `content`.
+    fn after_edit(uri: &str, content: &str, changes: &HashMap<Url, Vec<TextEdit>>) -> Option<Self> {
+        let edits = changes.get(&Url::parse(uri).ok()?)?;
+        Some(PendingCleanup {
+            uri: uri.to_string(),
+            content: apply_text_edits(content, edits),
+        })
+    }
+
+    /// Remove files the document no longer links to.
+    fn run(self, documents: &HashMap<String, String>) {
+        let media_dir = Url::parse(&self.uri)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .and_then(|path| resolve_media_dir(&path).ok());
+        if let Some(media_dir) = media_dir {
+            if let Err(e) = cleanup_old_diagram_files(&self.uri, &self.content, &media_dir, documents) {
+                warn!("Cleanup after rendering {} failed: {}", self.uri, e);
+            }
+        }
+    }
+}
+
+/// What a request sent to the client was for.
+enum PendingRequest {
+    /// `workspace/applyEdit`, with the cleanup to run once the edit is applied.
+    ApplyEdit {
+        label: String,
+        cleanup: Option<PendingCleanup>,
+    },
+    /// `window/showMessageRequest` asking to clear the shared cache serving `media_dirs`.
+    ConfirmClearCache { media_dirs: Vec<PathBuf> },
+    /// A request whose response only matters if it failed.
+    Other { method: String },
+}
+
+/// Requests sent to the client that are still awaiting a response.
+#[derive(Default)]
+struct PendingRequests {
+    last_id: i32,
+    requests: HashMap<RequestId, PendingRequest>,
+}
+
+impl PendingRequests {
+    /// Send a request to the client under a fresh ID, remembering what it was for.
+    fn send(
+        &mut self,
+        connection: &Connection,
+        method: &str,
+        params: impl serde::Serialize,
+        pending: PendingRequest,
+    ) -> Result<RequestId> {
+        self.last_id = self.last_id.wrapping_add(1);
+        let id = RequestId::from(self.last_id);
+        let request = Request::new(id.clone(), method.to_string(), params);
+        connection.sender.send(Message::Request(request))?;
+        self.requests.insert(id.clone(), pending);
+        Ok(id)
+    }
+
+    /// Forget a request, returning what it was for.
+    fn complete(&mut self, id: &RequestId) -> Option<PendingRequest> {
+        self.requests.remove(id)
+    }
+}
+
+/// Act on the client's response to one of our requests.
+fn handle_response(
+    connection: &Connection,
+    response: Response,
+    pending_requests: &mut PendingRequests,
+    documents: &HashMap<String, String>,
+) {
+    let Some(pending) = pending_requests.complete(&response.id) else {
+        debug!("Ignoring response to unknown request {:?}", response.id);
+        return;
+    };
+
+    match pending {
+        PendingRequest::ApplyEdit { label, cleanup } => match edit_outcome(&response) {
+            Ok(()) => {
+                info!("{} was applied", label);
+                if let Some(cleanup) = cleanup {
+                    cleanup.run(documents);
+                }
+            }
+            Err(reason) => {
+                warn!("{} was not applied: {}", label, reason);
+                send_error_notification(connection, &format!("{} was not applied: {}", label, reason));
+            }
+        },
+        PendingRequest::ConfirmClearCache { media_dirs } => {
+            let confirmed = response
+                .result
+                .and_then(|result| serde_json::from_value::<MessageActionItem>(result).ok())
+                .map(|action| action.title == CLEAR_SHARED_CACHE_ACTION)
+                .unwrap_or(false);
+            if !confirmed {
+                info!("Clearing the shared cache was cancelled");
+                return;
+            }
+            if let Err(e) = clear_caches(connection, &media_dirs) {
+                error!("Failed to clear the cache: {}", e);
+                send_error_notification(connection, &format!("Failed to clear the cache: {}", e));
+            }
+        }
+        PendingRequest::Other { method } => {
+            if let Some(error) = response.error {
+                warn!("{} request failed: {}", method, error.message);
+            }
+        }
+    }
+}
+
+/// Whether the client applied an edit, or why it did not.
+fn edit_outcome(response: &Response) -> std::result::Result<(), String> {
+    if let Some(error) = &response.error {
+        return Err(error.message.clone());
+    }
+    let result: ApplyWorkspaceEditResponse = response
+        .result
+        .clone()
+        .and_then(|result| serde_json::from_value(result).ok())
+        .ok_or_else(|| "invalid response from the editor".to_string())?;
+    if result.applied {
+        Ok(())
+    } else {
+        Err(result.failure_reason.unwrap_or_else(|| "rejected by the editor".to_string()))
+    }
+}
+
+/// Render new or changed fences after a save when `renderOnSave` is enabled.
+///
+/// Diagrams render in the background; the edit is sent once they are done (see `handle_save_outcome`).
+fn render_on_save(save_jobs: &SaveJobs, uri: &str, content: &str) {
+    let settings = config::current();
+    if !settings.render_on_save || settings.preview_mode == PreviewMode::Companion {
+        return;
+    }
+    if count_mermaid_blocks(content) == 0 {
+        return;
+    }
+
+    info!("Rendering diagrams on save for {}", uri);
+    save_jobs.queue(SaveJob::Render {
+        uri: uri.to_string(),
+        content: content.to_string(),
+    });
+}
+
+/// Send the edit rendered after a save, unless the document has changed since.
+fn apply_rendered_on_save(
+    connection: &Connection,
+    pending_requests: &mut PendingRequests,
+    documents: &HashMap<String, String>,
+    rendered: RenderedOnSave,
+) -> Result<()> {
+    for failure in rendered.failures {
+        let error_msg = format!("Failed to render diagram at line {}: {}", failure.line, failure.message);
+        error!("{}", error_msg);
+        send_error_notification(connection, &error_msg);
+    }
+    if rendered.changes.values().all(|edits| edits.is_empty()) {
+        return Ok(());
+    }
+    // The edits are positioned in the content that was rendered
+    if documents.get(&rendered.uri) != Some(&rendered.content) {
+        info!("{} changed while rendering on save; not applying the edit", rendered.uri);
+        return Ok(());
+    }
+
+    let cleanup = PendingCleanup::after_edit(&rendered.uri, &rendered.content, &rendered.changes);
+    let edit = WorkspaceEdit {
+        changes: Some(rendered.changes),
+        document_changes: None,
+        change_annotations: None,
+    };
+    apply_workspace_edit(connection, pending_requests, edit, "Render Mermaid Diagrams on Save", cleanup)
+}
+
+/// Queue every document whose debounce deadline has passed for validation.
+fn validate_due_documents(
+    validator: &Validator,
+    documents: &HashMap<String, String>,
+    pending_diagnostics: &mut HashMap<String, Instant>,
+) {
+    let now = Instant::now();
+    let due: Vec<String> = pending_diagnostics
+        .iter()
+        .filter(|(_, deadline)| **deadline <= now)
+        .map(|(uri, _)| uri.clone())
+        .collect();
+
+    for uri in due {
+        pending_diagnostics.remove(&uri);
+
+        if let Some(content) = documents.get(&uri) {
+            validator.validate(&uri, content, is_mermaid_document(&uri));
+        }
+    }
+}
+
+/// Publish diagnostics from the validator, unless the document has changed or closed since.
+fn publish_validation_result(connection: &Connection, documents: &HashMap<String, String>, result: ValidationResult) {
+    if documents.get(&result.uri) != Some(&result.content) {
+        debug!("Dropping outdated Mermaid diagnostics for {}", result.uri);
+        return;
+    }
+    let Ok(url) = Url::parse(&result.uri) else {
+        return;
+    };
+
+    debug!("Publishing {} Mermaid diagnostics for {}", result.diagnostics.len(), result.uri);
+    publish_diagnostics(connection, &url, result.diagnostics);
+}
+
+fn publish_diagnostics(connection: &Connection, uri: &Url, diagnostics: Vec<Diagnostic>) {
+    let params = PublishDiagnosticsParams {
+        uri: uri.clone(),
+        diagnostics,
+        version: None,
+    };
+
+    let notification = lsp_server::Notification {
+        method: "textDocument/publishDiagnostics".to_string(),
+        params: json!(params),
+    };
+
+    if let Err(e) = connection.sender.send(Message::Notification(notification)) {
+        error!("Failed to publish diagnostics: {}", e);
+    }
+}
+
+/// Directory in the system temp dir caching hover renders for documents without a `.mermaid` dir.
+const HOVER_MEDIA_DIR: &str = "mermaid-lsp-hover";
+
+/// Hover renders running in the background, and the errors of those that failed, by cache path.
+#[derive(Default)]
+struct HoverRenders {
+    running: HashSet<PathBuf>,
+    failed: HashMap<PathBuf, String>,
+}
+
+static HOVER_RENDERS: Lazy<Mutex<HoverRenders>> = Lazy::new(|| Mutex::new(HoverRenders::default()));
+
+/// Where hover renders are cached: the document's `.mermaid` directory if it already exists,
+/// otherwise a temporary one, so hovering never adds files to the project.
+fn hover_media_dir(path: &Path) -> PathBuf {
+    match resolve_media_dir(path) {
+        Ok(media_dir) if media_dir.is_dir() => media_dir,
+        _ => std::env::temp_dir().join(HOVER_MEDIA_DIR),
+    }
+}
+
+/// Render a diagram into the cache on a background thread, unless it is already being rendered.
+fn start_hover_render(code: String, options: RenderOptions, media_dir: PathBuf, cache_path: PathBuf) {
+    let Ok(mut renders) = HOVER_RENDERS.lock() else {
+        return;
+    };
+    if !renders.running.insert(cache_path.clone()) {
+        return;
+    }
+    drop(renders);
+
+    thread::spawn(move || {
+        let result = render_options_to_cache(&code, &options, &media_dir, ImageFormat::Svg);
+        if let Ok(mut renders) = HOVER_RENDERS.lock() {
+            renders.running.remove(&cache_path);
+            if let Err(e) = result {
+                debug!("Hover render failed: {}", e);
+                renders.failed.insert(cache_path, e.to_string());
+            }
+        }
+    });
+}
+
+/// Show the rendered diagram when hovering inside a mermaid fence or `.mmd` document.
+///
+/// Diagrams not yet in the cache are rendered in the background; until then the hover says so.
+fn get_hover(params: &HoverParams, documents: &HashMap<String, String>) -> Result<Option<Hover>> {
+    let uri = params.text_document_position_params.text_document.uri.to_string();
+    let position = params.text_document_position_params.position;
+
+    let Some(content) = documents.get(&uri) else {
+        return Ok(None);
+    };
+
+    let Some(block) = locate_mermaid_source_block(content, &uri, &position) else {
+        return Ok(None);
+    };
+
+    if block.code.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let path = Url::parse(&uri)?
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("Invalid file path"))?;
+
+    let media_dir = hover_media_dir(&path);
+    let options = config::current().render_options_for(&block.code);
+    let cache_path = cache_path_for(&block.code, &options, &media_dir, ImageFormat::Svg);
+
+    // A failure is reported once; hovering again retries the render
+    let failure = HOVER_RENDERS
+        .lock()
+        .ok()
+        .and_then(|mut renders| renders.failed.remove(&cache_path));
+
+    let markdown = if cache_path.exists() {
+        cache::record_access(&media_dir, &cache_path);
+        let image_url = Url::from_file_path(&cache_path)
+            .map_err(|_| anyhow::anyhow!("Invalid cache path: {:?}", cache_path))?;
+        markdown_image(&diagram_label(&block.code), image_url.as_str())
+    } else if let Some(error) = failure {
+        format!("**Mermaid render failed**\n\n```\n{}\n```", error)
+    } else {
+        start_hover_render(block.code.clone(), options, media_dir, cache_path);
+        "*Rendering Mermaid diagram…* Hover again in a moment to see it.".to_string()
+    };
+
+    Ok(Some(Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: markdown,
+        }),
+        range: Some(Range {
+            start: block.start,
+            end: block.end,
+        }),
+    }))
+}
+
 fn get_code_actions(
     params: &CodeActionParams,
     documents: &HashMap<String, String>,
@@ -411,8 +1071,95 @@ fn get_code_actions(
     let total_blocks = count_mermaid_blocks(content);
     info!("Found {} mermaid blocks, cursor at line {}", total_blocks, cursor.line);
 
+    let companion_mode = config::current().preview_mode == PreviewMode::Companion;
+
+    // Open Preview - renders into <doc>.preview.md without touching the document
+    let has_diagrams = total_blocks > 0 || is_mermaid_document(&uri);
+    if has_diagrams && !preview::is_preview_file(Path::new(params.text_document.uri.path())) {
+        actions.push(CodeAction {
+            title: "Open Mermaid Preview".to_string(),
+            kind: Some(CodeActionKind::EMPTY),
+            diagnostics: None,
+            edit: None,
+            command: Some(Command {
+                title: "Open Mermaid Preview".to_string(),
+                command: "mermaid.openPreview".to_string(),
+                arguments: Some(vec![json!({ "uri": uri })]),
+            }),
+            is_preferred: Some(companion_mode),
+            disabled: None,
+            data: None,
+        });
+    }
+
+    // Export PDF - one file per diagram, or all diagrams as pages of one file
+    if total_blocks > 0 && !preview::is_preview_file(Path::new(params.text_document.uri.path())) {
+        let mut exports = vec![("Export Mermaid Diagrams to PDF", false)];
+        if total_blocks > 1 {
+            exports.push(("Export Mermaid Diagrams to One PDF", true));
+        }
+        for (title, combined) in exports {
+            actions.push(CodeAction {
+                title: title.to_string(),
+                kind: Some(CodeActionKind::EMPTY),
+                diagnostics: None,
+                edit: None,
+                command: Some(Command {
+                    title: title.to_string(),
+                    command: "mermaid.exportPdf".to_string(),
+                    arguments: Some(vec![json!({ "uri": uri, "combined": combined })]),
+                }),
+                is_preferred: Some(false),
+                disabled: None,
+                data: None,
+            });
+        }
+    }
+
+    // Clear Cache - only once something has been cached next to this document
+    let has_cache = params
+        .text_document
+        .uri
+        .to_file_path()
+        .ok()
+        .and_then(|path| resolve_media_dir(&path).ok())
+        .map(|media_dir| cache::cache_root(&media_dir).is_dir())
+        .unwrap_or(false);
+    if has_cache {
+        actions.push(CodeAction {
+            title: "Clear Mermaid Cache".to_string(),
+            kind: Some(CodeActionKind::EMPTY),
+            diagnostics: None,
+            edit: None,
+            command: Some(Command {
+                title: "Clear Mermaid Cache".to_string(),
+                command: "mermaid.clearCache".to_string(),
+                arguments: Some(vec![json!({ "uri": uri })]),
+            }),
+            is_preferred: Some(false),
+            disabled: None,
+            data: None,
+        });
+        actions.push(CodeAction {
+            title: "Prune Mermaid Cache".to_string(),
+            kind: Some(CodeActionKind::EMPTY),
+            diagnostics: None,
+            edit: None,
+            command: Some(Command {
+                title: "Prune Mermaid Cache".to_string(),
+                command: "mermaid.pruneCache".to_string(),
+                arguments: Some(vec![json!({ "uri": uri })]),
+            }),
+            is_preferred: Some(false),
+            disabled: None,
+            data: None,
+        });
+    }
+
     // Render All - pre-compute edit for Zed compatibility
-    if total_blocks > 1 {
+    if companion_mode {
+        info!("Companion preview mode: not offering document rewrites");
+    } else if total_blocks > 1 {
         info!("Adding Render All action for {} diagrams (pre-computing edit)", total_blocks);
 
         // Pre-compute the WorkspaceEdit
@@ -477,31 +1224,71 @@ fn get_code_actions(
         }
     }
 
-    // Render Single - skip for now, only support bulk operations
-    // (Pre-computing single renders is complex and not needed for testing)
-
-    // Edit Mermaid action - only show when cursor is ON the HTML comment line
-    // This prevents confusion when cursor is on the image line
-    debug!("Checking if cursor is on a mermaid comment line...");
-
-    let lines: Vec<&str> = content.lines().collect();
-    let cursor_line = cursor.line.min((lines.len() - 1) as u32) as usize;
-
-    if cursor_line < lines.len() {
-        let line = lines[cursor_line].trim();
-        let is_on_comment = line.starts_with(MERMAID_SOURCE_COMMENT_PREFIX) && line.ends_with(MERMAID_SOURCE_COMMENT_SUFFIX);
-
-        debug!("Line {}: '{}' - is_comment: {}", cursor_line, line, is_on_comment);
+    // Render Single - the diagram under the cursor, rendered only when the command runs
+    if !companion_mode {
+        if let Some(block) = locate_mermaid_source_block(content, &uri, &cursor) {
+            debug!("Adding Render action for block at lines {}-{}", block.start.line, block.end.line);
+            actions.push(render_single_action(&uri, &block, total_blocks <= 1));
+        }
+    }
 
-        // Skip Edit Single for now - only support Edit All
-        debug!("Cursor state checked, skipping Edit Single action");
+    // Edit Mermaid Source - when the cursor is on a rendered block's comment or image line(s)
+    if let Some(block) = rendered_block_at(content, &uri, &cursor) {
+        debug!("Adding Edit Source action for block at lines {}-{}", block.start.line, block.end.line);
+        match create_source_edits(&uri, &block) {
+            Ok(changes) => {
+                actions.push(CodeAction {
+                    title: "Edit Mermaid Source".to_string(),
+                    kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                    diagnostics: None,
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(changes),
+                        document_changes: None,
+                        change_annotations: None,
+                    }),
+                    command: None,
+                    is_preferred: Some(false),
+                    disabled: None,
+                    data: None,
+                });
+            }
+            Err(e) => {
+                warn!("Failed to pre-compute Edit Source edit: {}", e);
+            }
+        }
     } else {
-        debug!("Not checking for edit actions");
+        debug!("Cursor is not on a rendered block, skipping Edit Source action");
     }
 
     Ok(actions)
 }
 
+/// Code action running `mermaid.renderSingle` for one diagram.
+fn render_single_action(uri: &str, block: &MermaidSourceBlock, is_preferred: bool) -> CodeAction {
+    CodeAction {
+        title: "Render Mermaid Diagram".to_string(),
+        kind: Some(CodeActionKind::REFACTOR_REWRITE),
+        diagnostics: None,
+        edit: None,
+        command: Some(Command {
+            title: "Render Mermaid Diagram".to_string(),
+            command: "mermaid.renderSingle".to_string(),
+            arguments: Some(vec![json!({
+                "uri": uri,
+                "startLine": block.start.line,
+                "endLine": block.end.line,
+                "endCharacter": block.end.character,
+                "code": block.code,
+                "name": block.name,
+            })]),
+        }),
+        // Render All is the preferred action when there is more than one diagram
+        is_preferred: Some(is_preferred),
+        disabled: None,
+        data: None,
+    }
+}
+
 // Removed script-related constants since we're using details wrapper
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -513,6 +1300,8 @@ enum DocumentKind {
 #[derive(Clone, Debug, Hash)]
 struct MermaidSourceBlock {
     code: String,
+    /// Explicit diagram id or document position, used to name output files.
+    name: String,
     start: Position,
     end: Position,
     kind: DocumentKind,
@@ -536,6 +1325,11 @@ fn locate_mermaid_source_block(
     cursor: &Position,
 ) -> Option<MermaidSourceBlock> {
     if is_mermaid_document(uri) {
+        // A rendered .mmd document holds a source comment and image, not diagram code
+        if count_rendered_blocks(content) > 0 || content.trim().is_empty() {
+            return None;
+        }
+
         let lines: Vec<&str> = content.lines().collect();
         let last_line = lines.len().saturating_sub(1);
         let end_character = lines.get(last_line).map(|l| l.len()).unwrap_or(0);
@@ -545,6 +1339,7 @@ fn locate_mermaid_source_block(
 
         return Some(MermaidSourceBlock {
             code: clean_code,
+            name: "0".to_string(),
             start: Position {
                 line: 0,
                 character: 0,
@@ -589,6 +1384,7 @@ fn locate_mermaid_source_block(
 
     Some(MermaidSourceBlock {
         code,
+        name: diagram_name(&lines, start_line),
         start: Position {
             line: start_line as u32,
             character: 0,
@@ -619,87 +1415,50 @@ fn locate_rendered_mermaid_block(
     debug!("=== locate_rendered_mermaid_block called ===");
     debug!("Cursor line: {}, total lines: {}", cursor_line, lines.len());
 
-    // Find comment with mermaid source file reference
+    // Find the rendered block the cursor is in: its source comment or inline source
     // Search BACKWARDS from cursor first (most common: cursor on image line after comment)
     // Then search forward if not found
-    debug!("Searching for mermaid comment near cursor line {}", cursor_line);
-
-    let source_line = {
-        // First, search backwards from cursor (up to 10 lines)
-        let search_start = cursor_line.saturating_sub(10);
-        let backward_result = (search_start..=cursor_line).rev().find(|&i| {
-            let line = lines[i].trim();
-            let is_comment = line.starts_with(MERMAID_SOURCE_COMMENT_PREFIX) && line.ends_with(MERMAID_SOURCE_COMMENT_SUFFIX);
-            if is_comment {
-                debug!("Found mermaid comment (backward) at line {}: {}", i, line);
-            }
-            is_comment
-        });
-
-        if let Some(line) = backward_result {
-            line
-        } else {
+    debug!("Searching for rendered block near cursor line {}", cursor_line);
+
+    let source_line = (0..=cursor_line)
+        .rev()
+        .find(|&i| is_rendered_block_start(lines[i]))
+        .filter(|&i| {
+            rendered_block_source(&lines, i)
+                .map(|(_, source_end)| rendered_images(&lines, source_end).end > cursor_line)
+                .unwrap_or(false)
+        })
+        .or_else(|| {
             // If not found backward, search forward (up to 5 lines)
             let search_end = (cursor_line + 5).min(lines.len() - 1);
-            let forward_result = (cursor_line..=search_end).find(|&i| {
-                let line = lines[i].trim();
-                let is_comment = line.starts_with(MERMAID_SOURCE_COMMENT_PREFIX) && line.ends_with(MERMAID_SOURCE_COMMENT_SUFFIX);
-                if is_comment {
-                    debug!("Found mermaid comment (forward) at line {}: {}", i, line);
-                }
-                is_comment
-            });
-
-            forward_result?
+            (cursor_line..=search_end).find(|&i| is_rendered_block_start(lines[i]))
+        })?;
+    debug!("Found rendered block at line {}: {}", source_line, lines[source_line]);
+
+    let (source, source_end) = rendered_block_source(&lines, source_line)?;
+
+    // source_file_path is relative to the document's parent
+    let document_dir = Url::parse(uri)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    debug!("Document directory: {:?}", document_dir);
+
+    // Read the source from the document or its file
+    let code = match source.read(&document_dir) {
+        Ok(content) => {
+            debug!("Read source {} ({} bytes)", source.describe(), content.len());
+            content
         }
-    };
-
-    // Extract the source file path
-    let line = lines[source_line].trim();
-    let file_start = MERMAID_SOURCE_COMMENT_PREFIX.len();
-    let file_end = line.len() - "-->".len();
-    let source_file_path = &line[file_start..file_end].trim();
-
-    // Get the full path to the source file
-    let source_full_path = if let Ok(url) = Url::parse(uri) {
-        if let Some(path) = url.to_file_path().ok() {
-            // source_file_path is relative to the document's parent
-            if let Some(parent) = path.parent() {
-                let full_path = parent.join(source_file_path);
-                debug!("Document path: {:?}", path);
-                debug!("Document parent: {:?}", parent);
-                debug!("Relative source file: {}", source_file_path);
-                debug!("Resolved full path: {:?}", full_path);
-
-                full_path
-            } else {
-                debug!("No parent directory for document, using relative path");
-                Path::new(source_file_path).to_path_buf()
-            }
-        } else {
-            debug!("Could not parse URI to file path: {}", uri);
-            Path::new(source_file_path).to_path_buf()
-        }
-    } else {
-        debug!("Could not parse URI: {}", uri);
-        Path::new(source_file_path).to_path_buf()
-    };
-
-    debug!("Looking for source file at: {:?}", source_full_path);
-    debug!("File exists: {}", source_full_path.exists());
-
-    // Read the source from the file
-    let code = match fs::read_to_string(&source_full_path) {
-        Ok(content) => {
-            debug!("Successfully read source file ({} bytes)", content.len());
-            content
-        }
-        Err(e) => {
-            debug!("Failed to read source file: {}, attempting to find recent file...", e);
-            debug!("Error details: {:?}", e.kind());
+        Err(e) => {
+            debug!("Failed to read source {}: {}, attempting to find recent file...", source.describe(), e);
 
             // Try to find the most recent matching file
-            if let Some(recent_code) = find_most_recent_source_file(&source_full_path, &uri) {
+            let BlockSource::File(source_file_path) = source else {
+                return None;
+            };
+            if let Some(recent_code) = find_most_recent_source_file(&document_dir.join(source_file_path), uri) {
                 debug!("Found recent source file, using that instead");
                 recent_code
             } else {
@@ -709,30 +1468,15 @@ fn locate_rendered_mermaid_block(
         }
     };
 
-    // Find the image reference (usually on the next non-empty line)
-    let mut img_line = source_line + 1;
-    while img_line < lines.len() && lines[img_line].trim().is_empty() {
-        img_line += 1;
-    }
-
-    // Find the end of the block (after the image and any trailing blank lines)
-    let end_line = if img_line < lines.len() && lines[img_line].contains("![Mermaid Diagram](") {
-        // Start after the image line
-        let mut end = img_line + 1;
-
-        // Skip ONE blank line if present (common formatting)
-        if end < lines.len() && lines[end].trim().is_empty() {
-            end += 1;
-        }
-
-        // But stop if we hit another diagram or content
-        // Don't consume the next diagram's comment or headers
-        end
+    // Find the end of the block (after the image(s) and one trailing blank line)
+    let images = rendered_images(&lines, source_end);
+    let end_line = if images.paths.is_empty() {
+        source_end + 2
     } else {
-        source_line + 2
+        images.end
     };
 
-    debug!("Found rendered block - comment line {}, img line {}, end line {}", source_line, img_line, end_line);
+    debug!("Found rendered block - comment line {}, images {:?}, end line {}", source_line, images.paths, end_line);
 
 
     Some(RenderedMermaidBlock {
@@ -753,6 +1497,12 @@ fn locate_rendered_mermaid_block(
     })
 }
 
+/// The rendered block the cursor is on, from its source comment to its last image line.
+fn rendered_block_at(content: &str, uri: &str, cursor: &Position) -> Option<RenderedMermaidBlock> {
+    locate_rendered_mermaid_block(content, uri, cursor)
+        .filter(|block| block.start.line <= cursor.line && cursor.line < block.end.line.max(block.start.line + 1))
+}
+
 fn find_mermaid_fence(lines: &[&str], cursor_line: usize) -> Option<(usize, usize)> {
     let mut opening = None;
 
@@ -776,41 +1526,357 @@ fn find_mermaid_fence(lines: &[&str], cursor_line: usize) -> Option<(usize, usiz
     Some((start, end))
 }
 
-/// Clean up old diagram files that are no longer referenced in the document
-/// Keeps cache files (.cache/*.svg) but removes unreferenced output files
-fn cleanup_old_diagram_files(_uri: &str, content: &str, media_dir: &Path) -> Result<()> {
-    info!("=== CLEANUP: Cleaning up old diagram files in {:?}", media_dir);
+/// Path in a `<!-- mermaid-source-file:... -->` comment on this line, if any.
+fn source_comment_path(line: &str) -> Option<&str> {
+    let start = line.find(MERMAID_SOURCE_COMMENT_PREFIX)? + MERMAID_SOURCE_COMMENT_PREFIX.len();
+    let end = line[start..].find(MERMAID_SOURCE_COMMENT_SUFFIX)?;
+    Some(line[start..start + end].trim())
+}
 
-    // Find all currently referenced files in the document
-    let mut referenced_files = std::collections::HashSet::new();
-    for line in content.lines() {
-        if line.contains(MERMAID_SOURCE_COMMENT_PREFIX) {
-            // Extract the .mmd file path from comment
-            if let Some(start) = line.find(MERMAID_SOURCE_COMMENT_PREFIX) {
-                let path_start = start + MERMAID_SOURCE_COMMENT_PREFIX.len();
-                if let Some(end) = line[path_start..].find(MERMAID_SOURCE_COMMENT_SUFFIX) {
-                    let file_path = line[path_start..path_start + end].trim();
-                    referenced_files.insert(file_path.to_string());
+/// Where a rendered block keeps its diagram source.
+#[derive(Debug, PartialEq)]
+enum BlockSource<'a> {
+    /// A `.mmd` file, relative to the document, named in a `<!-- mermaid-source-file: -->` comment.
+    File(&'a str),
+    /// Source stored in the document itself.
+    Inline(String),
+}
+
+impl BlockSource<'_> {
+    /// The diagram source; `.mmd` files are resolved against the document's directory.
+    fn read(&self, document_dir: &Path) -> std::io::Result<String> {
+        match self {
+            BlockSource::File(path) => fs::read_to_string(document_dir.join(path)),
+            BlockSource::Inline(code) => Ok(code.clone()),
+        }
+    }
+
+    /// How problems with this source are described to the user.
+    fn describe(&self) -> String {
+        match self {
+            BlockSource::File(path) => format!("'{}'", path),
+            BlockSource::Inline(_) => "the inline source".to_string(),
+        }
+    }
+}
+
+/// Whether a rendered block starts on this line.
+fn is_rendered_block_start(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with(MERMAID_SOURCE_COMMENT_PREFIX)
+        || line == MERMAID_INLINE_SOURCE_START
+        || line == MERMAID_SOURCE_DETAILS_START
+}
+
+/// The source of a rendered block starting on `line`, and the last line it occupies.
+///
+/// Recognizes a `<!-- mermaid-source-file: -->` pointer, a `<!-- mermaid-source ... -->`
+/// comment and a collapsed `<details>` block holding an `mmd` fence.
+fn rendered_block_source<'a>(lines: &[&'a str], line: usize) -> Option<(BlockSource<'a>, usize)> {
+    let trimmed = lines.get(line)?.trim();
+
+    if trimmed.starts_with(MERMAID_SOURCE_COMMENT_PREFIX) {
+        return Some((BlockSource::File(source_comment_path(trimmed)?), line));
+    }
+
+    if trimmed == MERMAID_INLINE_SOURCE_START {
+        let close = (line + 1..lines.len()).find(|&i| lines[i].trim() == MERMAID_SOURCE_COMMENT_SUFFIX)?;
+        let code = unescape_comment(&lines[line + 1..close].join("\n"));
+        return Some((BlockSource::Inline(code), close));
+    }
+
+    if trimmed == MERMAID_SOURCE_DETAILS_START {
+        let close = (line + 1..lines.len()).find(|&i| lines[i].trim() == MERMAID_SOURCE_DETAILS_END)?;
+        let open = (line + 1..close).find(|&i| lines[i].trim() == MERMAID_SOURCE_DETAILS_FENCE)?;
+        let fence_end = (open + 1..close).find(|&i| lines[i].trim_start().starts_with(MERMAID_FENCE_END))?;
+        return Some((BlockSource::Inline(lines[open + 1..fence_end].join("\n")), close));
+    }
+
+    None
+}
+
+/// Markup storing a diagram's source: a pointer to its `.mmd` file, or the source itself.
+fn source_markup(storage: SourceStorage, source_file: &str, code: &str) -> String {
+    let code = code.trim_end();
+    match storage {
+        SourceStorage::File => format!(
+            "{}{}{}\n",
+            MERMAID_SOURCE_COMMENT_PREFIX, source_file, MERMAID_SOURCE_COMMENT_SUFFIX
+        ),
+        SourceStorage::Comment => format!(
+            "{}\n{}\n{}\n",
+            MERMAID_INLINE_SOURCE_START, escape_comment(code), MERMAID_SOURCE_COMMENT_SUFFIX
+        ),
+        SourceStorage::Details => format!(
+            "{}\n\n{}\n{}\n{}\n\n{}\n",
+            MERMAID_SOURCE_DETAILS_START, MERMAID_SOURCE_DETAILS_FENCE, code, MERMAID_FENCE_END,
+            MERMAID_SOURCE_DETAILS_END
+        ),
+    }
+}
+
+/// Make diagram source safe inside an HTML comment, which `-->` arrows would otherwise close.
+///
+/// Escapes `>` after `--` or `--!` as `&gt;`, and `&` as `&amp;` where it would
+/// otherwise be read back as one of those escapes.
+fn escape_comment(code: &str) -> String {
+    let mut escaped = String::with_capacity(code.len());
+    for (i, c) in code.char_indices() {
+        match c {
+            '>' if escaped.ends_with("--") || escaped.ends_with("--!") => escaped.push_str("&gt;"),
+            '&' if code[i..].starts_with("&gt;") || code[i..].starts_with("&amp;") => escaped.push_str("&amp;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverse `escape_comment`.
+fn unescape_comment(escaped: &str) -> String {
+    let mut code = String::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("&gt;") {
+            code.push('>');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("&amp;") {
+            code.push('&');
+            rest = after;
+        } else {
+            code.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    code
+}
+
+/// Target of a rendered diagram's Markdown image link on this line, if any.
+///
+/// Links into `.mermaid/`, embedded `data:` images and the original `![Mermaid Diagram]`
+/// links are recognized whatever their alt text. A `"title"` and URL fragments such as
+/// GitHub's `#gh-dark-mode-only` are not part of the path.
+fn image_link_path(line: &str) -> Option<&str> {
+    let image = &line[line.find("![")?..];
+    let start = image.find("](")? + 2;
+    let target = &image[start..];
+    let end = match (target.find(" \""), target.find(')')) {
+        (Some(title), Some(close)) => title.min(close),
+        (title, close) => title.or(close)?,
+    };
+    let target = &target[..end];
+    let path = target.split('#').next().unwrap_or(target).trim();
+
+    let is_rendered = image.starts_with("![Mermaid Diagram](")
+        || path.starts_with(&format!("{}/", MERMAID_MEDIA_DIR))
+        || path.starts_with("data:image/");
+    is_rendered.then_some(path)
+}
+
+/// Markdown image link for a rendered diagram, with alt text and title from its label.
+fn markdown_image(label: &DiagramLabel, target: &str) -> String {
+    // Brackets would end the alt text early, and quotes the title
+    let alt = single_line(label.alt_text()).replace('[', "(").replace(']', ")");
+    match label.title.as_deref().map(single_line) {
+        Some(title) if title != alt => format!("![{}]({} \"{}\")", alt, target, title.replace('"', "'")),
+        _ => format!("![{}]({})", alt, target),
+    }
+}
+
+/// Collapse whitespace, including newlines, to single spaces.
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A base64 `data:` URI embedding an image in the document.
+fn data_uri(format: ImageFormat, contents: &[u8]) -> String {
+    format!(
+        "data:{};base64,{}",
+        format.mime_type(),
+        base64::engine::general_purpose::STANDARD.encode(contents)
+    )
+}
+
+/// Contents of a base64 `data:` URI, or `None` for anything else.
+fn decode_data_uri(uri: &str) -> Option<Vec<u8>> {
+    let (_, data) = uri.strip_prefix("data:")?.split_once(";base64,")?;
+    base64::engine::general_purpose::STANDARD.decode(data).ok()
+}
+
+/// Value of an HTML attribute on this line, e.g. `src` in `<img src="...">`.
+fn html_attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let marker = format!(" {}=\"", name);
+    let start = line.find(&marker)? + marker.len();
+    let end = line[start..].find('"')?;
+    Some(&line[start..start + end])
+}
+
+/// The images of a rendered block and the line just past it.
+#[derive(Debug, PartialEq)]
+struct RenderedImages<'a> {
+    /// Linked image paths, the light rendition first.
+    paths: Vec<&'a str>,
+    /// First line after the block, including one trailing blank line.
+    end: usize,
+}
+
+/// Find the image markup following a block's source, which ends on `source_end`: a single
+/// image link, a `#gh-*-mode-only` image pair, or a `<picture>` element.
+fn rendered_images<'a>(lines: &[&'a str], source_end: usize) -> RenderedImages<'a> {
+    let mut line = source_end + 1;
+    while line < lines.len() && lines[line].trim().is_empty() {
+        line += 1;
+    }
+
+    let mut paths = Vec::new();
+    let mut end = source_end + 1;
+    if line < lines.len() && lines[line].trim() == "<picture>" {
+        if let Some(close) = (line..lines.len()).find(|&i| lines[i].trim() == "</picture>") {
+            for element in &lines[line + 1..close] {
+                if let Some(path) = html_attribute(element, "srcset").or_else(|| html_attribute(element, "src")) {
+                    paths.push(path);
                 }
             }
+            // The <img> fallback is the light rendition
+            paths.reverse();
+            end = close + 1;
         }
-        // Also collect SVG references from markdown image links
-        if line.contains("![Mermaid Diagram](") {
-            if let Some(start) = line.find("](") {
-                let path_start = start + 2;
-                if let Some(end) = line[path_start..].find(')') {
-                    let file_path = line[path_start..path_start + end].trim();
-                    referenced_files.insert(file_path.to_string());
-                }
+    } else {
+        while line < lines.len() {
+            let Some(path) = image_link_path(lines[line]) else {
+                break;
+            };
+            paths.push(path);
+            line += 1;
+            end = line;
+        }
+    }
+
+    // Skip ONE blank line if present (common formatting)
+    if !paths.is_empty() && end < lines.len() && lines[end].trim().is_empty() {
+        end += 1;
+    }
+
+    RenderedImages { paths, end }
+}
+
+/// Every `.mermaid` file (source comments and image links) a document refers to.
+///
+/// Both renditions of a linked image count, so files written by `outputFormat: both` are kept.
+fn referenced_media_files(content: &str) -> std::collections::HashSet<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut referenced_files = std::collections::HashSet::new();
+    for (index, line) in lines.iter().enumerate() {
+        let mut images: Vec<&str> = image_link_path(line).into_iter().collect();
+        // Extract the .mmd file path from comment, and the images (e.g. a <picture>) after the source
+        if let Some((source, source_end)) = rendered_block_source(&lines, index) {
+            if let BlockSource::File(file_path) = source {
+                referenced_files.insert(file_path.to_string());
+            }
+            images.extend(rendered_images(&lines, source_end).paths);
+        }
+        // Also collect image references from markdown image links
+        for file_path in images.into_iter().filter(|path| !path.starts_with("data:")) {
+            referenced_files.insert(file_path.to_string());
+            // With `outputFormat: both` the other format sits next to the linked one
+            for format in [ImageFormat::Svg, ImageFormat::Png] {
+                let sibling = Path::new(file_path).with_extension(format.extension());
+                referenced_files.insert(sibling.to_string_lossy().to_string());
             }
         }
     }
+    referenced_files
+}
+
+/// Whether `file_name` is an output name `<stem>_<diagram>_<content hash>.<ext>` for `stem`.
+fn is_output_name_for(file_name: &str, stem: &str) -> bool {
+    let Some((base, extension)) = file_name.rsplit_once('.') else {
+        return false;
+    };
+    if !matches!(extension, "mmd" | "svg" | "png") {
+        return false;
+    }
+    let Some((name, hash)) = base
+        .strip_prefix(stem)
+        .and_then(|rest| rest.strip_prefix('_'))
+        .and_then(|rest| rest.rsplit_once('_'))
+    else {
+        return false;
+    };
+    let is_hash = hash.len() == CONTENT_HASH_LENGTH && hash.chars().all(|c| c.is_ascii_hexdigit());
+    let is_name = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    is_hash && is_name
+}
+
+/// Clean up old diagram files that are no longer referenced in the document
+///
+/// Only files named for this document are removed: `doc.md` never touches `doc_v2.md`'s
+/// `doc_v2_0_<hash>.svg`, which belongs to the document with the longer matching stem.
+/// Files still linked from any document in the same folder are kept, reading open documents
+/// from `open_documents` (which may not be saved yet) and the rest from disk.
+fn cleanup_old_diagram_files(
+    uri: &str,
+    content: &str,
+    media_dir: &Path,
+    open_documents: &HashMap<String, String>,
+) -> Result<()> {
+    info!("=== CLEANUP: Cleaning up old diagram files in {:?}", media_dir);
+
+    let document = Url::parse(uri)?
+        .to_file_path()
+        .map_err(|_| anyhow!("Invalid file path: {}", uri))?;
+    let stem = document
+        .file_stem()
+        .ok_or_else(|| anyhow!("Document has no file name: {:?}", document))?
+        .to_string_lossy()
+        .to_string();
+    let folder = document.parent().unwrap_or_else(|| Path::new("."));
+
+    // Find all currently referenced files in the document
+    let mut referenced_files = referenced_media_files(content);
 
     info!("CLEANUP: Found {} referenced files in document", referenced_files.len());
     for ref_file in &referenced_files {
         info!("CLEANUP: Referenced: {}", ref_file);
     }
 
+    // ...and in its neighbours: open ones as edited, the rest as saved on disk
+    let is_document = |path: &Path| {
+        path.extension()
+            .map(|ext| ext == "md" || ext == "markdown" || ext == "mmd")
+            .unwrap_or(false)
+    };
+    let mut neighbours: HashMap<PathBuf, Option<&str>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir(folder) {
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if is_document(&path) {
+                neighbours.insert(path, None);
+            }
+        }
+    }
+    for (open_uri, open_content) in open_documents {
+        let Some(path) = Url::parse(open_uri).ok().and_then(|url| url.to_file_path().ok()) else {
+            continue;
+        };
+        if path.parent() == Some(folder) && is_document(&path) {
+            neighbours.insert(path, Some(open_content.as_str()));
+        }
+    }
+    neighbours.remove(&document);
+
+    let mut neighbour_stems = Vec::new();
+    for (path, open_content) in &neighbours {
+        if let Some(neighbour_stem) = path.file_stem() {
+            neighbour_stems.push(neighbour_stem.to_string_lossy().to_string());
+        }
+        let neighbour_content = match open_content {
+            Some(open_content) => Some(open_content.to_string()),
+            None => fs::read_to_string(path).ok(),
+        };
+        if let Some(neighbour_content) = neighbour_content {
+            referenced_files.extend(referenced_media_files(&neighbour_content));
+        }
+    }
+
     // Scan the media directory for orphaned files
     if let Ok(entries) = std::fs::read_dir(media_dir) {
         for entry in entries.flatten() {
@@ -821,19 +1887,20 @@ fn cleanup_old_diagram_files(_uri: &str, content: &str, media_dir: &Path) -> Res
                 continue;
             }
 
-            // Only clean up .mmd and .svg files
-            if let Some(ext) = path.extension() {
-                if ext != "mmd" && ext != "svg" {
-                    continue;
-                }
-            } else {
+            // Only this document's generated .mmd, .svg and .png files
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            if !is_output_name_for(&file_name, &stem) {
+                continue;
+            }
+            let claimed_by_neighbour = neighbour_stems.iter().any(|neighbour_stem| {
+                neighbour_stem.len() > stem.len() && is_output_name_for(&file_name, neighbour_stem)
+            });
+            if claimed_by_neighbour {
                 continue;
             }
 
             // Check if this file is referenced
-            let file_name = path.file_name().unwrap().to_string_lossy();
             let relative_path = format!("{}/{}", MERMAID_MEDIA_DIR, file_name);
-
             if !referenced_files.contains(file_name.as_ref()) &&
                !referenced_files.contains(&relative_path) {
                 info!("CLEANUP: Removing unreferenced file: {:?}", path);
@@ -849,17 +1916,164 @@ fn cleanup_old_diagram_files(_uri: &str, content: &str, media_dir: &Path) -> Res
     Ok(())
 }
 
-fn create_render_edits(
-    uri: &str,
-    block: &MermaidSourceBlock,
-) -> Result<HashMap<Url, Vec<TextEdit>>> {
-    info!("=== create_render_edits called for URI: {} ===", uri);
-    let url = Url::parse(uri)?;
-    let path = url
-        .to_file_path()
-        .map_err(|_| anyhow::anyhow!("Invalid file path"))?;
-    info!("File path: {:?}", path);
+/// Stable hash of diagram source, used in output filenames.
+fn content_hash(code: &str) -> String {
+    let digest = Sha256::digest(code.trim_end().as_bytes());
+    digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()[..CONTENT_HASH_LENGTH]
+        .to_string()
+}
+
+/// Name of the diagram starting at `fence_line`: its explicit id, or its position in the document.
+///
+/// The position counts both unrendered fences and already rendered blocks, so rendering
+/// one diagram does not renumber the ones after it.
+fn diagram_name(lines: &[&str], fence_line: usize) -> String {
+    if let Some(caps) = lines
+        .get(fence_line)
+        .and_then(|line| line.trim_start().strip_prefix(MERMAID_FENCE_START))
+        .and_then(|info| DIAGRAM_ID_REGEX.captures(info))
+    {
+        return caps[1].to_string();
+    }
+
+    let mut index = 0;
+    let mut i = 0;
+    while i < fence_line.min(lines.len()) {
+        let line = lines[i].trim_start();
+        if is_rendered_block_start(line) {
+            index += 1;
+        } else if line.starts_with(MERMAID_FENCE_START) {
+            let is_rendered = i > 0 && lines[i - 1].trim_start().starts_with(MERMAID_SOURCE_COMMENT_PREFIX);
+            if !is_rendered {
+                index += 1;
+            }
+            // Skip the fence body so ``` lines inside it are not misread
+            while i + 1 < fence_line && !lines[i + 1].trim_start().starts_with(MERMAID_FENCE_END) {
+                i += 1;
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+
+    index.to_string()
+}
+
+/// Prefix an SVG with a comment recording the hash of the source it was rendered from.
+fn stamp_source_hash(svg: &str, hash: &str) -> String {
+    format!("{}{}{}\n{}", MERMAID_SOURCE_HASH_PREFIX, hash, MERMAID_SOURCE_COMMENT_SUFFIX, svg)
+}
+
+/// Source hash recorded by [`stamp_source_hash`], if present.
+fn recorded_source_hash(svg: &str) -> Option<&str> {
+    let rest = svg.trim_start().strip_prefix(MERMAID_SOURCE_HASH_PREFIX)?;
+    let end = rest.find(MERMAID_SOURCE_COMMENT_SUFFIX)?;
+    Some(rest[..end].trim())
+}
+
+/// Record the hash of the source a PNG was rendered from in a `tEXt` chunk after its header.
+///
+/// Returns the PNG unchanged if it does not start with an `IHDR` chunk.
+fn stamp_png_source_hash(png: &[u8], hash: &str) -> Vec<u8> {
+    // Signature, then IHDR: length, type, 13 bytes of data and CRC
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+    if png.len() < IHDR_END || &png[12..16] != b"IHDR" {
+        return png.to_vec();
+    }
+
+    let data = [PNG_SOURCE_HASH_KEYWORD.as_bytes(), b"\0", hash.as_bytes()].concat();
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(b"tEXt");
+    crc.update(&data);
+    let chunk = [
+        &(data.len() as u32).to_be_bytes()[..],
+        b"tEXt",
+        &data,
+        &crc.finalize().to_be_bytes(),
+    ]
+    .concat();
+
+    [&png[..IHDR_END], &chunk, &png[IHDR_END..]].concat()
+}
+
+/// Source hash recorded by [`stamp_png_source_hash`], if present.
+fn recorded_png_source_hash(png: &[u8]) -> Option<&str> {
+    let mut offset = 8;
+    while let Some(header) = png.get(offset..offset + 8) {
+        let length = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+        let data = png.get(offset + 8..offset + 8 + length)?;
+        if &header[4..] == b"tEXt" {
+            let hash = data
+                .strip_prefix(PNG_SOURCE_HASH_KEYWORD.as_bytes())
+                .and_then(|rest| rest.strip_prefix(b"\0"));
+            if let Some(hash) = hash {
+                return std::str::from_utf8(hash).ok();
+            }
+        }
+        offset += 12 + length;
+    }
+    None
+}
+
+/// Source hash recorded in a rendered SVG or PNG, if present.
+fn recorded_image_source_hash(image: &[u8]) -> Option<&str> {
+    match std::str::from_utf8(image) {
+        Ok(svg) => recorded_source_hash(svg),
+        Err(_) => recorded_png_source_hash(image),
+    }
+}
+
+/// Base filename (without extension) for a diagram's rendered outputs:
+/// `<document stem>_<diagram name>_<content hash>`.
+fn output_base_name(document: &Path, name: &str, code: &str) -> String {
+    let stem = document
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "diagram".to_string());
+    format!("{}_{}_{}", stem, name, content_hash(code))
+}
+
+/// Write `contents` unless the file already holds exactly those bytes.
+fn write_if_changed(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if fs::read(path).map(|existing| existing == contents).unwrap_or(false) {
+        return Ok(());
+    }
+    fs::write(path, contents)
+}
+
+/// Whether rendering may write diagram files to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WriteMode {
+    Write,
+    /// Render to validate and compute edits, but leave the filesystem untouched.
+    DryRun,
+}
+
+/// A diagram that could not be rendered.
+#[derive(Clone, Debug)]
+struct RenderFailure {
+    /// 1-based line of the opening fence.
+    line: usize,
+    message: String,
+}
 
+/// Resolve (and create) the `.mermaid` media directory next to a document.
+fn media_dir_for(path: &Path) -> Result<PathBuf> {
+    let media_dir = resolve_media_dir(path)?;
+
+    // Ensure the mermaid media directory exists
+    fs::create_dir_all(&media_dir)
+        .map_err(|e| anyhow!("Failed to create mermaid media directory: {}", e))?;
+
+    Ok(media_dir)
+}
+
+/// Locate the `.mermaid` media directory next to a document without creating it.
+fn resolve_media_dir(path: &Path) -> Result<PathBuf> {
     // Create mermaid media directory in the document's parent directory
     // SECURITY: Validate path stays within project boundaries
     let media_dir = if let Some(parent) = path.parent() {
@@ -895,73 +2109,201 @@ fn create_render_edits(
         Path::new(MERMAID_MEDIA_DIR).to_path_buf()
     };
 
-    // Ensure the mermaid media directory exists
-    fs::create_dir_all(&media_dir)
-        .map_err(|e| anyhow!("Failed to create mermaid media directory: {}", e))?;
+    Ok(media_dir)
+}
+
+/// Media directories a cache command applies to: the one next to the `uri` argument,
+/// or those next to every open document.
+fn command_media_dirs(params: &ExecuteCommandParams, documents: &HashMap<String, String>) -> Vec<PathBuf> {
+    let uris: Vec<String> = match params.arguments
+        .first()
+        .and_then(|arg| arg.get("uri"))
+        .and_then(|v| v.as_str())
+    {
+        Some(uri) => vec![uri.to_string()],
+        None => documents.keys().cloned().collect(),
+    };
+
+    let mut media_dirs: Vec<PathBuf> = uris
+        .iter()
+        .filter_map(|uri| Url::parse(uri).ok()?.to_file_path().ok())
+        .filter_map(|path| resolve_media_dir(&path).ok())
+        .collect();
+    media_dirs.sort();
+    media_dirs.dedup();
+    media_dirs
+}
 
+/// Clear the render caches serving `media_dirs` and report how much was removed.
+fn clear_caches(connection: &Connection, media_dirs: &[PathBuf]) -> Result<()> {
+    let mut removed = 0;
+    for media_dir in media_dirs {
+        removed += cache::clear_cache(media_dir)?;
+    }
+
+    send_info_notification(connection, &format!("Cleared {} cached diagram(s)", removed));
+    Ok(())
+}
+
+/// Ask the user before clearing the user-level cache shared by every workspace.
+fn confirm_clear_shared_cache(
+    connection: &Connection,
+    pending_requests: &mut PendingRequests,
+    media_dirs: Vec<PathBuf>,
+) -> Result<()> {
+    let params = ShowMessageRequestParams {
+        typ: MessageType::WARNING,
+        message: "Mermaid: the render cache is shared by every workspace. Clear it for all of them?".to_string(),
+        actions: Some(vec![MessageActionItem {
+            title: CLEAR_SHARED_CACHE_ACTION.to_string(),
+            properties: HashMap::new(),
+        }]),
+    };
+    pending_requests.send(
+        connection,
+        "window/showMessageRequest",
+        params,
+        PendingRequest::ConfirmClearCache { media_dirs },
+    )?;
+    Ok(())
+}
+
+/// Path of the cached render of a diagram with the given options and format.
+fn cache_path_for(code: &str, options: &RenderOptions, media_dir: &Path, format: ImageFormat) -> PathBuf {
+    let extension = format.extension();
+    cache::cache_path(media_dir, &cache::cache_key(code, options, extension), extension)
+}
+
+/// Render through the `.mermaid/.cache` directory, returning the cached SVG path and contents.
+fn render_to_cache(code: &str, media_dir: &Path) -> Result<(PathBuf, String)> {
+    let (cache_path, contents) = render_format_to_cache(code, media_dir, ImageFormat::Svg)?;
+    let svg = String::from_utf8(contents)
+        .map_err(|e| anyhow!("Failed to read cached SVG: {}", e))?;
+    Ok((cache_path, svg))
+}
+
+/// Render one format through the cache, returning the cached file and its contents.
+fn render_format_to_cache(code: &str, media_dir: &Path, format: ImageFormat) -> Result<(PathBuf, Vec<u8>)> {
+    let options = config::current().render_options_for(code);
+    render_options_to_cache(code, &options, media_dir, format)
+}
+
+/// Render one format with explicit options through the cache.
+fn render_options_to_cache(
+    code: &str,
+    options: &RenderOptions,
+    media_dir: &Path,
+    format: ImageFormat,
+) -> Result<(PathBuf, Vec<u8>)> {
     // Create cache directory
-    let cache_dir = media_dir.join(MERMAID_CACHE_DIR);
-    fs::create_dir_all(&cache_dir)
+    fs::create_dir_all(cache::cache_dir(media_dir))
         .map_err(|e| anyhow!("Failed to create cache directory: {}", e))?;
 
-    // Generate a hash of the mermaid code for caching
-    let mut hasher = DefaultHasher::new();
-    block.code.hash(&mut hasher);
-    let code_hash = hasher.finish();
-    let cache_filename = format!("mermaid_{:x}.svg", code_hash);
-    let cache_path = cache_dir.join(&cache_filename);
-
-    // Check if we have a cached version
-    let svg_contents = if cache_path.exists() {
-        debug!("Using cached SVG for hash {:x}", code_hash);
-        fs::read_to_string(&cache_path)
-            .map_err(|e| anyhow!("Failed to read cached SVG: {}", e))?
-    } else {
-        debug!("Rendering new SVG (cache miss) for hash {:x}", code_hash);
-        let contents = render_mermaid(&block.code)?;
+    let cache_path = cache_path_for(code, options, media_dir, format);
 
-        // Cache the result
-        fs::write(&cache_path, contents.as_bytes())
-            .map_err(|e| anyhow!("Failed to write cached SVG: {}", e))?;
+    // Check if we have a cached version; another server sharing the cache may evict it at any time
+    let contents = if let Ok(contents) = fs::read(&cache_path) {
+        debug!("Using cached render {:?}", cache_path);
+        cache::record_access(media_dir, &cache_path);
+        contents
+    } else {
+        debug!("Rendering new {} (cache miss) for {:?}", format.extension(), cache_path);
+        let contents = render_mermaid_format_with(code, options, format)?;
+
+        // Cache the result; other servers may be reading (or clearing) the same shared cache
+        match cache::write_atomic(&cache_path, &contents) {
+            Ok(()) => {
+                cache::record_access(media_dir, &cache_path);
+                cache::maybe_evict(media_dir, config::current().cache_limits());
+            }
+            Err(e) => warn!("Failed to cache render: {}", e),
+        }
 
         contents
     };
 
-    // Generate unique filename for output (not cache)
-    let counter = SVG_COUNTER.fetch_add(1, Ordering::SeqCst);
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let unique_id = format!("{}_{}", timestamp, counter);
+    Ok((cache_path, contents))
+}
 
-    let svg_filename = match path.file_stem() {
-        Some(stem) => {
-            let stem_str = stem.to_string_lossy();
-            format!("{}_diagram_{}.svg", stem_str, unique_id)
-        }
-        None => format!("diagram_{}.svg", unique_id),
-    };
+fn create_render_edits(
+    uri: &str,
+    block: &MermaidSourceBlock,
+    mode: WriteMode,
+) -> Result<HashMap<Url, Vec<TextEdit>>> {
+    info!("=== create_render_edits called for URI: {} ===", uri);
+    let url = Url::parse(uri)?;
+    let path = url
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("Invalid file path"))?;
+    info!("File path: {:?}", path);
 
-    let svg_path = media_dir.join(&svg_filename);
+    // Deterministic output names: re-rendering unchanged source rewrites nothing
+    let base_name = output_base_name(&path, &block.name, &block.code);
+    let settings = config::current();
+    let output_format = settings.output_format;
+
+    // The dark rendition is named like its own diagram so it keeps the hash suffix
+    let mut variants = vec![(base_name.clone(), settings.render_options_for(&block.code))];
+    if settings.dark_mode != DarkMode::Off {
+        variants.push((
+            output_base_name(&path, &format!("{}-dark", block.name), &block.code),
+            settings.dark_variant().render_options_for(&block.code),
+        ));
+    }
 
-    info!("Writing SVG to: {:?}", svg_path);
-    // Copy from cache to output location
-    fs::write(&svg_path, svg_contents.as_bytes())
-        .map_err(|e| anyhow!("Failed to write SVG: {}", e))?;
-    info!("Successfully wrote SVG file");
+    let media_dir = resolve_media_dir(&path)?;
+    let source_file_path = media_dir.join(format!("{}{}", base_name, MERMAID_FILE_EXTENSION));
+    let media_dir = match mode {
+        WriteMode::Write => media_dir_for(&path)?,
+        WriteMode::DryRun => media_dir,
+    };
 
-    let source_file_path = {
-        let base_name = path.file_stem()
-            .unwrap_or_default()
-            .to_string_lossy();
-        let source_filename = format!("{}_{}{}", base_name, unique_id, MERMAID_FILE_EXTENSION);
-        media_dir.join(source_filename)
+    // Embedded images travel inside the document, so only the linked format is needed
+    let formats = if settings.embed_images {
+        &output_format.formats()[..1]
+    } else {
+        output_format.formats()
     };
 
-    // Write the source to the .mmd file
-    fs::write(&source_file_path, &block.code)
-        .map_err(|e| anyhow!("Failed to write source file: {}", e))?;
+    let mut embedded = Vec::new();
+    for (variant_name, options) in &variants {
+        for &format in formats {
+            let contents = match mode {
+                WriteMode::Write => render_options_to_cache(&block.code, options, &media_dir, format)?.1,
+                // Still render uncached diagrams so broken ones are reported
+                WriteMode::DryRun => match fs::read(cache_path_for(&block.code, options, &media_dir, format)) {
+                    Ok(contents) => contents,
+                    Err(_) => render_mermaid_format_with(&block.code, options, format)?,
+                },
+            };
+
+            // Images record which source they were rendered from
+            let contents = match format {
+                ImageFormat::Svg => {
+                    let svg = String::from_utf8_lossy(&contents);
+                    stamp_source_hash(&svg, &content_hash(&block.code)).into_bytes()
+                }
+                ImageFormat::Png => stamp_png_source_hash(&contents, &content_hash(&block.code)),
+                ImageFormat::Pdf => contents,
+            };
+
+            if settings.embed_images {
+                embedded.push(data_uri(format, &contents));
+            } else if mode == WriteMode::Write {
+                // Copy from cache to output location
+                let image_path = media_dir.join(format!("{}.{}", variant_name, format.extension()));
+                info!("Writing {} to: {:?}", format.extension(), image_path);
+                write_if_changed(&image_path, &contents)
+                    .map_err(|e| anyhow!("Failed to write {}: {}", format.extension(), e))?;
+            }
+        }
+    }
+
+    if mode == WriteMode::Write && settings.source_storage == SourceStorage::File {
+        // Write the source to the .mmd file
+        write_if_changed(&source_file_path, block.code.as_bytes())
+            .map_err(|e| anyhow!("Failed to write source file: {}", e))?;
+    }
 
     // Calculate relative paths from the markdown file to mermaid media directory
     let source_relative = source_file_path
@@ -969,16 +2311,25 @@ fn create_render_edits(
         .unwrap_or(&source_file_path)
         .to_string_lossy();
 
-    let svg_path_buf = Path::new(MERMAID_MEDIA_DIR).join(&svg_filename);
-    let svg_relative = svg_path_buf.to_string_lossy();
+    let image_links: Vec<String> = if settings.embed_images {
+        embedded
+    } else {
+        variants
+            .iter()
+            .map(|(variant_name, _)| {
+                let image_filename = format!("{}.{}", variant_name, output_format.linked().extension());
+                Path::new(MERMAID_MEDIA_DIR).join(image_filename).to_string_lossy().to_string()
+            })
+            .collect()
+    };
 
     let mut new_text = format!(
-        "{}{}{}\n\n![Mermaid Diagram]({})\n",
-        MERMAID_SOURCE_COMMENT_PREFIX, source_relative, MERMAID_SOURCE_COMMENT_SUFFIX,
-        svg_relative
+        "{}\n{}",
+        source_markup(settings.source_storage, &source_relative, &block.code),
+        image_markup(settings.dark_mode, &image_links, &diagram_label(&block.code))
     );
 
-    debug!("Rendering with external source file");
+    debug!("Rendering with {:?} source storage", settings.source_storage);
 
     if !new_text.ends_with('\n') {
         new_text.push('\n');
@@ -999,6 +2350,41 @@ fn create_render_edits(
     Ok(changes)
 }
 
+/// Markup linking a diagram's image, or its light and dark renditions in that order.
+///
+/// GitHub ignores its `#gh-*-mode-only` fragments on `data:` URIs, so embedded
+/// renditions always use a `<picture>` element.
+fn image_markup(dark_mode: DarkMode, images: &[String], label: &DiagramLabel) -> String {
+    match (dark_mode, images) {
+        (DarkMode::Github, [light, dark]) if !light.starts_with("data:") => format!(
+            "{}\n{}\n",
+            markdown_image(label, &format!("{}#gh-light-mode-only", light)),
+            markdown_image(label, &format!("{}#gh-dark-mode-only", dark))
+        ),
+        (DarkMode::Picture | DarkMode::Github, [light, dark]) => {
+            let mut img = format!(
+                "<img src=\"{}\" alt=\"{}\"",
+                light,
+                html_escape::encode_double_quoted_attribute(&single_line(label.alt_text()))
+            );
+            if let Some(title) = &label.title {
+                img.push_str(&format!(
+                    " title=\"{}\"",
+                    html_escape::encode_double_quoted_attribute(&single_line(title))
+                ));
+            }
+            format!(
+                "<picture>\n  <source media=\"(prefers-color-scheme: dark)\" srcset=\"{}\">\n  {}>\n</picture>\n",
+                dark, img
+            )
+        }
+        _ => images
+            .iter()
+            .map(|image| format!("{}\n", markdown_image(label, image)))
+            .collect(),
+    }
+}
+
 fn create_source_edits(
     uri: &str,
     block: &RenderedMermaidBlock,
@@ -1061,7 +2447,7 @@ fn count_rendered_blocks(content: &str) -> usize {
     let mut count = 0;
 
     for line in lines {
-        if line.trim().starts_with(MERMAID_SOURCE_COMMENT_PREFIX) {
+        if is_rendered_block_start(line) {
             count += 1;
         }
     }
@@ -1079,73 +2465,59 @@ fn edit_all_sources_content(
 
     debug!("Searching for rendered blocks to edit...");
 
+    let document_dir = Url::parse(uri)?
+        .to_file_path()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+
     while i < lines.len() {
-        let line = lines[i].trim();
-
-        // Look for mermaid source comment lines
-        if line.starts_with(MERMAID_SOURCE_COMMENT_PREFIX) && line.ends_with(MERMAID_SOURCE_COMMENT_SUFFIX) {
-            debug!("Found rendered block at line {}", i);
-
-            // Find the end of the rendered block (next blank line or mermaid fence)
-            let mut end = i + 1;
-            while end < lines.len() {
-                let next_line = lines[end].trim();
-                if next_line.is_empty() || next_line.starts_with("```mermaid") || next_line.starts_with(MERMAID_SOURCE_COMMENT_PREFIX) {
-                    break;
-                }
-                end += 1;
-            }
+        // Look for rendered blocks: a source comment or inline source, then the image(s)
+        let Some((source, source_end)) = rendered_block_source(&lines, i) else {
+            i += 1;
+            continue;
+        };
+        debug!("Found rendered block at line {}", i);
 
-            // Extract the source file path from comment
-            let start_pos = line.find(MERMAID_SOURCE_COMMENT_PREFIX).unwrap() + MERMAID_SOURCE_COMMENT_PREFIX.len();
-            let end_pos = line.len() - MERMAID_SOURCE_COMMENT_SUFFIX.len();
-            let source_file = &line[start_pos..end_pos];
-
-            debug!("Loading source from: {}", source_file);
-
-            // Read the source file
-            if let Ok(source_url) = Url::parse(uri) {
-                if let Ok(doc_path) = source_url.to_file_path() {
-                    if let Some(parent) = doc_path.parent() {
-                        let source_path = parent.join(source_file);
-                        if let Ok(source_code) = std::fs::read_to_string(&source_path) {
-                            // Create the block
-                            let block = RenderedMermaidBlock {
-                                code: source_code,
-                                start: Position {
-                                    line: i as u32,
-                                    character: 0,
-                                },
-                                end: Position {
-                                    line: end as u32,
-                                    character: 0,
-                                },
-                                kind: DocumentKind::Markdown,
-                            };
-
-                            match create_source_edits(uri, &block) {
-                                Ok(mut edits) => {
-                                    if let Some((url, mut text_edits)) = edits.drain().next() {
-                                        if let Some(existing_edits) = all_edits.get_mut(&url) {
-                                            existing_edits.append(&mut text_edits);
-                                        } else {
-                                            all_edits.insert(url, text_edits);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("Failed to create source edits for line {}: {}", i + 1, e);
-                                }
+        // The rendered block ends after its image link(s) or <picture> element
+        let end = rendered_images(&lines, source_end).end;
+
+        match source.read(&document_dir) {
+            Ok(source_code) => {
+                let block = RenderedMermaidBlock {
+                    code: source_code,
+                    start: Position {
+                        line: i as u32,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: end as u32,
+                        character: 0,
+                    },
+                    kind: DocumentKind::Markdown,
+                };
+
+                match create_source_edits(uri, &block) {
+                    Ok(mut edits) => {
+                        if let Some((url, mut text_edits)) = edits.drain().next() {
+                            if let Some(existing_edits) = all_edits.get_mut(&url) {
+                                existing_edits.append(&mut text_edits);
+                            } else {
+                                all_edits.insert(url, text_edits);
                             }
                         }
                     }
+                    Err(e) => {
+                        warn!("Failed to create source edits for line {}: {}", i + 1, e);
+                    }
                 }
             }
-
-            i = end;
-        } else {
-            i += 1;
+            Err(e) => {
+                warn!("Failed to read source {} for line {}: {}", source.describe(), i + 1, e);
+            }
         }
+
+        i = end;
     }
 
     debug!("Found {} sets of edits across all rendered blocks", all_edits.len());
@@ -1157,20 +2529,32 @@ fn render_all_diagrams_content(
     content: &str,
     connection: Option<&Connection>,
 ) -> Result<HashMap<Url, Vec<TextEdit>>> {
+    let (all_edits, failures) = render_all_diagrams(uri, content, WriteMode::Write);
+
+    for failure in failures {
+        let error_msg = format!("Failed to render diagram at line {}: {}", failure.line, failure.message);
+        error!("{}", error_msg);
+        if let Some(conn) = connection {
+            send_error_notification(conn, &error_msg);
+        }
+    }
+
+    Ok(all_edits)
+}
+
+/// Fenced diagrams in a document that have not been rendered yet, in document order.
+fn unrendered_diagram_blocks(uri: &str, content: &str) -> Vec<MermaidSourceBlock> {
     let lines: Vec<&str> = content.lines().collect();
-    let mut all_edits: HashMap<Url, Vec<TextEdit>> = HashMap::new();
-    let mut rendered_any = false;  // Track if we actually rendered anything
+    let mut blocks = Vec::new();
     let mut i = 0;
 
     while i < lines.len() {
         if let Some((start, end)) = find_mermaid_fence(&lines, i) {
             // Skip if already rendered
             if start == 0 || !lines[start - 1].starts_with(MERMAID_SOURCE_COMMENT_PREFIX) {
-                rendered_any = true;  // Mark that we're rendering something
-                let code = lines[start + 1..end].join("\n");
-
-                let block = MermaidSourceBlock {
-                    code,
+                blocks.push(MermaidSourceBlock {
+                    code: lines[start + 1..end].join("\n"),
+                    name: diagram_name(&lines, start),
                     start: Position {
                         line: start as u32,
                         character: 0,
@@ -1191,26 +2575,7 @@ fn render_all_diagrams_content(
                     } else {
                         DocumentKind::Markdown
                     },
-                };
-
-                match create_render_edits(uri, &block) {
-                    Ok(mut edits) => {
-                        if let Some((url, mut text_edits)) = edits.drain().next() {
-                            if let Some(existing_edits) = all_edits.get_mut(&url) {
-                                existing_edits.append(&mut text_edits);
-                            } else {
-                                all_edits.insert(url, text_edits);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let error_msg = format!("Failed to render diagram at line {}: {}", start + 1, e);
-                        error!("{}", error_msg);
-                        if let Some(conn) = connection {
-                            send_error_notification(conn, &error_msg);
-                        }
-                    }
-                }
+                });
             }
             i = end + 1;
         } else {
@@ -1218,23 +2583,60 @@ fn render_all_diagrams_content(
         }
     }
 
+    blocks
+}
+
+/// Render every unrendered fence in a document, collecting the edits and any failures.
+fn render_all_diagrams(
+    uri: &str,
+    content: &str,
+    mode: WriteMode,
+) -> (HashMap<Url, Vec<TextEdit>>, Vec<RenderFailure>) {
+    let mut all_edits: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+    let mut failures = Vec::new();
+    let blocks = unrendered_diagram_blocks(uri, content);
+    let rendered_any = !blocks.is_empty();  // Track if we actually rendered anything
+
+    for block in &blocks {
+        match create_render_edits(uri, block, mode) {
+            Ok(mut edits) => {
+                if let Some((url, mut text_edits)) = edits.drain().next() {
+                    if let Some(existing_edits) = all_edits.get_mut(&url) {
+                        existing_edits.append(&mut text_edits);
+                    } else {
+                        all_edits.insert(url, text_edits);
+                    }
+                }
+            }
+            Err(e) => {
+                failures.push(RenderFailure {
+                    line: block.start.line as usize + 1,
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+
     // IMPORTANT: Do NOT run cleanup here!
-    // When called from CodeAction pre-computation, the edits haven't been applied yet,
-    // so cleanup sees the old content and deletes all the newly created SVG files.
-    // Cleanup should only run during actual command execution when we have the updated content.
+    // The edits haven't been applied yet, so cleanup would see the old content and delete
+    // the newly created SVG files. Commands that send the edits themselves clean up once the
+    // client confirms it applied them (see `PendingCleanup`).
     if rendered_any {
-        info!("Rendered new diagrams, but skipping cleanup (not safe during pre-computation)");
+        info!("Rendered new diagrams; cleanup waits until the edit is applied");
     } else {
-        info!("No new diagrams rendered (all already rendered), skipping cleanup");
+        info!("No new diagrams rendered (all already rendered)");
     }
 
-    Ok(all_edits)
+    (all_edits, failures)
 }
 
+/// Ask the client to apply an edit, running `cleanup` once it reports the edit applied.
 fn apply_workspace_edit(
     connection: &Connection,
+    pending_requests: &mut PendingRequests,
     edit: WorkspaceEdit,
     label: &str,
+    cleanup: Option<PendingCleanup>,
 ) -> Result<()> {
     info!("Sending workspace/applyEdit request: {}", label);
 
@@ -1243,22 +2645,40 @@ fn apply_workspace_edit(
         edit,
     };
 
-    let request = Request::new(
-        RequestId::from(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos() as i32),
-        "workspace/applyEdit".to_string(),
-        serde_json::to_value(params)?
-    );
-
-    connection.sender.send(Message::Request(request))?;
+    pending_requests.send(
+        connection,
+        "workspace/applyEdit",
+        params,
+        PendingRequest::ApplyEdit {
+            label: label.to_string(),
+            cleanup,
+        },
+    )?;
     info!("workspace/applyEdit request sent successfully");
 
     Ok(())
 }
 
+fn show_document(connection: &Connection, pending_requests: &mut PendingRequests, uri: Url) -> Result<()> {
+    info!("Sending window/showDocument request: {}", uri);
+
+    let params = ShowDocumentParams {
+        uri,
+        external: Some(false),
+        take_focus: Some(false),
+        selection: None,
+    };
+
+    let method = "window/showDocument";
+    pending_requests.send(connection, method, params, PendingRequest::Other { method: method.to_string() })?;
+    Ok(())
+}
+
 fn execute_command(
     params: &ExecuteCommandParams,
     documents: &HashMap<String, String>,
     connection: &Connection,
+    pending_requests: &mut PendingRequests,
 ) -> Result<()> {
     info!("=== EXECUTE COMMAND: {} ===", params.command);
 
@@ -1277,6 +2697,7 @@ fn execute_command(
 
             info!("Rendering all diagrams for {}", uri);
             let changes = render_all_diagrams_content(uri, content, Some(connection))?;
+            let cleanup = PendingCleanup::after_edit(uri, content, &changes);
 
             let edit = WorkspaceEdit {
                 changes: Some(changes),
@@ -1285,7 +2706,7 @@ fn execute_command(
             };
 
             // Send workspace/applyEdit to Zed
-            apply_workspace_edit(connection, edit, "Render All Mermaid Diagrams")?;
+            apply_workspace_edit(connection, pending_requests, edit, "Render All Mermaid Diagrams", cleanup)?;
             Ok(())
         }
         "mermaid.renderSingle" => {
@@ -1309,6 +2730,12 @@ fn execute_command(
                 .and_then(|v| v.as_u64())
                 .ok_or_else(|| anyhow::anyhow!("Missing endLine"))? as u32;
 
+            // A block ending on the document's last line ends within it
+            let end_character = args
+                .get("endCharacter")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0) as u32;
+
             let code = args
                 .get("code")
                 .and_then(|v| v.as_str())
@@ -1316,21 +2743,37 @@ fn execute_command(
 
             info!("Rendering single diagram for {}", uri);
 
+            let name = match args.get("name").and_then(|v| v.as_str()) {
+                Some(name) => name.to_string(),
+                None => documents
+                    .get(uri)
+                    .map(|content| diagram_name(&content.lines().collect::<Vec<_>>(), start_line as usize))
+                    .unwrap_or_else(|| start_line.to_string()),
+            };
+
             // Create the block
             let block = MermaidSourceBlock {
                 code: code.to_string(),
+                name,
                 start: Position {
                     line: start_line,
                     character: 0,
                 },
                 end: Position {
                     line: end_line,
-                    character: 0,
+                    character: end_character,
+                },
+                kind: if is_mermaid_document(uri) {
+                    DocumentKind::Mermaid
+                } else {
+                    DocumentKind::Markdown
                 },
-                kind: DocumentKind::Markdown,
             };
 
-            let changes = create_render_edits(uri, &block)?;
+            let changes = create_render_edits(uri, &block, WriteMode::Write)?;
+            let cleanup = documents
+                .get(uri)
+                .and_then(|content| PendingCleanup::after_edit(uri, content, &changes));
 
             let edit = WorkspaceEdit {
                 changes: Some(changes),
@@ -1339,7 +2782,7 @@ fn execute_command(
             };
 
             // Send workspace/applyEdit to Zed
-            apply_workspace_edit(connection, edit, "Render Mermaid Diagram")?;
+            apply_workspace_edit(connection, pending_requests, edit, "Render Mermaid Diagram", cleanup)?;
             Ok(())
         }
         "mermaid.editSingleSource" => {
@@ -1390,7 +2833,30 @@ fn execute_command(
                 change_annotations: None,
             };
 
-            apply_workspace_edit(connection, edit, "Edit Mermaid Source")?;
+            apply_workspace_edit(connection, pending_requests, edit, "Edit Mermaid Source", None)?;
+            Ok(())
+        }
+        "mermaid.openPreview" => {
+            let uri = params.arguments
+                .first()
+                .and_then(|arg| arg.get("uri"))
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow::anyhow!("Missing URI argument"))?;
+
+            let content = documents
+                .get(uri)
+                .ok_or_else(|| anyhow::anyhow!("Document not found: {}", uri))?;
+
+            let path = Url::parse(uri)?
+                .to_file_path()
+                .map_err(|_| anyhow::anyhow!("Invalid file path"))?;
+
+            info!("Writing companion preview for {}", uri);
+            let preview_path = preview::write_preview(&path, content, is_mermaid_document(uri))?;
+            let preview_url = Url::from_file_path(&preview_path)
+                .map_err(|_| anyhow::anyhow!("Invalid preview path: {:?}", preview_path))?;
+
+            show_document(connection, pending_requests, preview_url)?;
             Ok(())
         }
         "mermaid.editAllSources" => {
@@ -1413,9 +2879,598 @@ fn execute_command(
                 change_annotations: None,
             };
 
-            apply_workspace_edit(connection, edit, "Edit All Mermaid Sources")?;
+            apply_workspace_edit(connection, pending_requests, edit, "Edit All Mermaid Sources", None)?;
+            Ok(())
+        }
+        "mermaid.clearCache" => {
+            let media_dirs = command_media_dirs(params, documents);
+            if config::current().shared_cache {
+                // The shared cache serves every workspace, so only clear it once confirmed
+                return confirm_clear_shared_cache(connection, pending_requests, media_dirs);
+            }
+            clear_caches(connection, &media_dirs)
+        }
+        "mermaid.pruneCache" => {
+            let limits = config::current().cache_limits();
+            let mut removed = 0;
+            for media_dir in command_media_dirs(params, documents) {
+                removed += cache::evict(&media_dir, limits)?;
+            }
+
+            send_info_notification(connection, &format!("Evicted {} cached diagram(s)", removed));
+            Ok(())
+        }
+        "mermaid.exportPdf" => {
+            let args = params.arguments.first();
+            let uri = args
+                .and_then(|arg| arg.get("uri"))
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow::anyhow!("Missing URI argument"))?;
+            let combined = args
+                .and_then(|arg| arg.get("combined"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+
+            let content = documents
+                .get(uri)
+                .ok_or_else(|| anyhow::anyhow!("Document not found: {}", uri))?;
+
+            info!("Exporting mermaid diagrams to PDF for {} (combined: {})", uri, combined);
+            let export = export::export_pdf(uri, content, combined)?;
+
+            for failure in &export.failures {
+                send_error_notification(
+                    connection,
+                    &format!("Failed to export diagram at line {}: {}", failure.line, failure.message),
+                );
+            }
+            if !export.files.is_empty() {
+                let files: Vec<String> = export
+                    .files
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                send_info_notification(connection, &format!("Exported {}", files.join(", ")));
+            }
             Ok(())
         }
         _ => Err(anyhow::anyhow!("Unknown command: {}", params.command)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hash_is_stable_and_short() {
+        let hash = content_hash("flowchart TD\n    A --> B");
+        assert_eq!(hash.len(), CONTENT_HASH_LENGTH);
+        assert_eq!(hash, content_hash("flowchart TD\n    A --> B\n"));
+        assert_ne!(hash, content_hash("flowchart TD\n    A --> C"));
+    }
+
+    #[test]
+    fn diagrams_are_named_by_position_or_explicit_id() {
+        let content = "# Doc\n\n```mermaid\npie\n```\n\n<!-- mermaid-source-file:.mermaid/doc_1_abc.mmd-->\n\n![Mermaid Diagram](.mermaid/doc_1_abc.svg)\n\n```mermaid\nflowchart TD\n```\n\n```mermaid id=login-flow\nsequenceDiagram\n```\n\n```mermaid {#deploy}\nflowchart LR\n```\n";
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(diagram_name(&lines, 2), "0");
+        assert_eq!(diagram_name(&lines, 10), "2");
+        assert_eq!(diagram_name(&lines, 14), "login-flow");
+        assert_eq!(diagram_name(&lines, 18), "deploy");
+    }
+
+    #[test]
+    fn labels_image_links() {
+        let label = diagram_label("flowchart TD\n    accTitle: Login flow\n    accDescr: User signs in\n    A --> B");
+        let link = markdown_image(&label, ".mermaid/doc_0_abc.svg");
+        assert_eq!(link, "![User signs in](.mermaid/doc_0_abc.svg \"Login flow\")");
+        assert_eq!(image_link_path(&link), Some(".mermaid/doc_0_abc.svg"));
+
+        // Unrelated images are still not mistaken for rendered diagrams
+        assert_eq!(image_link_path("![Login flow](docs/login.png \"Login\")"), None);
+    }
+
+    #[test]
+    fn locates_the_diagram_to_render_under_the_cursor() {
+        let content = "# Doc\n\n```mermaid\npie\n```\n\nText\n";
+        let at = |line| Position { line, character: 0 };
+
+        let block = locate_mermaid_source_block(content, "file:///docs/doc.md", &at(3)).unwrap();
+        assert_eq!(block.code, "pie");
+        assert_eq!((block.start.line, block.end.line), (2, 5));
+        assert!(locate_mermaid_source_block(content, "file:///docs/doc.md", &at(6)).is_none());
+
+        // A .mmd document is one diagram, until it has been rendered
+        let block = locate_mermaid_source_block("flowchart TD\n    A --> B\n", "file:///docs/flow.mmd", &at(0)).unwrap();
+        assert_eq!(block.kind, DocumentKind::Mermaid);
+        let rendered = "<!-- mermaid-source-file:.mermaid/flow_0_abc.mmd-->\n\n![Mermaid Diagram](.mermaid/flow_0_abc.svg)\n";
+        assert!(locate_mermaid_source_block(rendered, "file:///docs/flow.mmd", &at(0)).is_none());
+    }
+
+    #[test]
+    fn edit_source_applies_to_the_block_under_the_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join(MERMAID_MEDIA_DIR)).unwrap();
+        fs::write(dir.path().join(".mermaid/doc_0_abc.mmd"), "pie").unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap().to_string();
+
+        let content = "# Doc\n\nIntro\n\n<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->\n\n![Mermaid Diagram](.mermaid/doc_0_abc.svg)\n\nAfter\n";
+        let at = |line| Position { line, character: 0 };
+
+        for line in [4, 6] {
+            let block = rendered_block_at(content, &uri, &at(line)).unwrap();
+            assert_eq!((block.start.line, block.end.line), (4, 8));
+            let changes = create_source_edits(&uri, &block).unwrap();
+            assert_eq!(changes.values().next().unwrap()[0].new_text, "```mermaid\npie\n```\n");
+        }
+        // Nearby text is not part of the block
+        assert!(rendered_block_at(content, &uri, &at(2)).is_none());
+        assert!(rendered_block_at(content, &uri, &at(8)).is_none());
+    }
+
+    #[test]
+    fn parses_source_comments_and_image_links() {
+        assert_eq!(
+            source_comment_path("<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->"),
+            Some(".mermaid/doc_0_abc.mmd")
+        );
+        assert_eq!(
+            image_link_path("![Mermaid Diagram](.mermaid/doc_0_abc.svg)"),
+            Some(".mermaid/doc_0_abc.svg")
+        );
+        assert_eq!(
+            image_link_path("![Mermaid Diagram](.mermaid/doc_0_abc.svg#gh-dark-mode-only)"),
+            Some(".mermaid/doc_0_abc.svg")
+        );
+        assert_eq!(image_link_path("![Screenshot](shot.png)"), None);
+    }
+
+    #[test]
+    fn both_renditions_of_a_linked_image_are_referenced() {
+        let content = "<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->\n\n![Mermaid Diagram](.mermaid/doc_0_abc.png)\n";
+        let referenced = referenced_media_files(content);
+        assert!(referenced.contains(".mermaid/doc_0_abc.mmd"));
+        assert!(referenced.contains(".mermaid/doc_0_abc.png"));
+        assert!(referenced.contains(".mermaid/doc_0_abc.svg"));
+    }
+
+    #[test]
+    fn recognizes_dark_mode_image_markup() {
+        let comment = "<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->\n\n";
+        let light = ".mermaid/doc_0_abc.svg".to_string();
+        let dark = ".mermaid/doc_0-dark_abc.svg".to_string();
+
+        let labelled = DiagramLabel {
+            title: Some("Login \"flow\" (v2)".to_string()),
+            description: Some("User [signs in](x) & sees\nthe dashboard".to_string()),
+        };
+        let cases = [DarkMode::Off, DarkMode::Picture, DarkMode::Github]
+            .into_iter()
+            .flat_map(|dark_mode| [(dark_mode, DiagramLabel::default()), (dark_mode, labelled.clone())]);
+
+        for (dark_mode, label) in cases {
+            let images = if dark_mode == DarkMode::Off {
+                vec![light.clone()]
+            } else {
+                vec![light.clone(), dark.clone()]
+            };
+            let content = format!("{}{}\n# Next", comment, image_markup(dark_mode, &images, &label));
+            let lines: Vec<&str> = content.lines().collect();
+
+            let rendered = rendered_images(&lines, 0);
+            assert_eq!(rendered.paths, images, "{:?}", dark_mode);
+            assert_eq!(lines[rendered.end], "# Next", "{:?}", dark_mode);
+            assert!(referenced_media_files(&content).contains(images.last().unwrap().as_str()));
+        }
+    }
+
+    #[test]
+    fn edit_all_sources_restores_picture_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join(MERMAID_MEDIA_DIR)).unwrap();
+        fs::write(dir.path().join(".mermaid/doc_0_abc.mmd"), "pie\n    \"A\" : 1\n").unwrap();
+        let document = dir.path().join("doc.md");
+        let uri = Url::from_file_path(&document).unwrap().to_string();
+
+        let images = vec![".mermaid/doc_0_abc.svg".to_string(), ".mermaid/doc_0-dark_abc.svg".to_string()];
+        let content = format!(
+            "# Doc\n\n<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->\n\n{}\nAfter\n",
+            image_markup(DarkMode::Picture, &images, &DiagramLabel::default())
+        );
+
+        let changes = edit_all_sources_content(&uri, &content).unwrap();
+        let edits = &changes[&Url::parse(&uri).unwrap()];
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range.start.line, 2);
+        assert_eq!(edits[0].range.end.line, 9);
+        assert_eq!(edits[0].new_text, "```mermaid\npie\n    \"A\" : 1\n```\n");
+    }
+
+    #[test]
+    fn comment_escaping_round_trips() {
+        for code in [
+            "flowchart TD\n    A --> B\n    B --x C",
+            "A &gt; B --!> C & D &amp; E",
+            "sequenceDiagram\n    A->>B: --> \"&gt;\"",
+        ] {
+            let escaped = escape_comment(code);
+            assert!(!escaped.contains("-->") && !escaped.contains("--!>"), "{}", escaped);
+            assert_eq!(unescape_comment(&escaped), code);
+        }
+    }
+
+    #[test]
+    fn inline_sources_restore_without_files() {
+        let code = "flowchart TD\n    A --> B";
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap().to_string();
+
+        for storage in [SourceStorage::Comment, SourceStorage::Details] {
+            let content = format!(
+                "# Doc\n\n{}\n{}\nAfter\n",
+                source_markup(storage, ".mermaid/doc_0_abc.mmd", code),
+                image_markup(DarkMode::Off, &[".mermaid/doc_0_abc.svg".to_string()], &DiagramLabel::default())
+            );
+            let lines: Vec<&str> = content.lines().collect();
+            assert_eq!(count_rendered_blocks(&content), 1, "{:?}", storage);
+
+            let (source, source_end) = rendered_block_source(&lines, 2).unwrap();
+            assert_eq!(source, BlockSource::Inline(code.to_string()), "{:?}", storage);
+            assert_eq!(rendered_images(&lines, source_end).paths, vec![".mermaid/doc_0_abc.svg"]);
+
+            let changes = edit_all_sources_content(&uri, &content).unwrap();
+            let edits = &changes[&Url::parse(&uri).unwrap()];
+            assert_eq!(edits[0].new_text, format!("```mermaid\n{}\n```\n", code), "{:?}", storage);
+            assert_eq!(lines[edits[0].range.end.line as usize], "After", "{:?}", storage);
+
+            let block = locate_rendered_mermaid_block(&content, &uri, &Position { line: 4, character: 0 }).unwrap();
+            assert_eq!(block.code, code);
+        }
+    }
+
+    #[test]
+    fn embedded_images_round_trip_through_data_uris() {
+        let svg = stamp_source_hash("<svg></svg>", "0123456789ab");
+        let uri = data_uri(ImageFormat::Svg, svg.as_bytes());
+        assert!(uri.starts_with("data:image/svg+xml;base64,"));
+        assert_eq!(decode_data_uri(&uri), Some(svg.into_bytes()));
+        assert_eq!(decode_data_uri(".mermaid/doc_0_abc.svg"), None);
+
+        // The link is recognised, but names no file in .mermaid
+        let content = format!(
+            "<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->\n\n{}",
+            image_markup(DarkMode::Off, std::slice::from_ref(&uri), &DiagramLabel::default())
+        );
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(rendered_images(&lines, 0).paths, vec![uri.as_str()]);
+        assert_eq!(referenced_media_files(&content).len(), 1);
+    }
+
+    #[test]
+    fn embedded_dark_renditions_use_a_picture_element() {
+        let images = vec![
+            data_uri(ImageFormat::Svg, b"<svg>light</svg>"),
+            data_uri(ImageFormat::Svg, b"<svg>dark</svg>"),
+        ];
+        let markup = image_markup(DarkMode::Github, &images, &DiagramLabel::default());
+        assert!(markup.starts_with("<picture>"), "{}", markup);
+        assert!(!markup.contains("#gh-"));
+
+        let content = format!("<!-- mermaid-source-file:.mermaid/doc_0_abc.mmd-->\n\n{}", markup);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(rendered_images(&lines, 0).paths, images);
+    }
+
+    #[test]
+    fn source_hash_round_trips_through_png() {
+        let png = render::Renderer::render_png(&render::TestRenderer, "pie", &RenderOptions::default()).unwrap();
+        assert_eq!(recorded_png_source_hash(&png), None);
+
+        let stamped = stamp_png_source_hash(&png, "0123456789ab");
+        assert!(stamped.starts_with(&png[..33]));
+        assert!(stamped.ends_with(&png[33..]));
+        assert_eq!(recorded_image_source_hash(&stamped), Some("0123456789ab"));
+
+        // Not a PNG this can stamp
+        assert_eq!(stamp_png_source_hash(b"\x89PNG", "0123456789ab"), b"\x89PNG");
+    }
+
+    #[test]
+    fn source_hash_round_trips_through_svg() {
+        let svg = stamp_source_hash("<svg></svg>", "0123456789ab");
+        assert!(svg.ends_with("<svg></svg>"));
+        assert_eq!(recorded_source_hash(&svg), Some("0123456789ab"));
+        assert_eq!(recorded_source_hash("<svg></svg>"), None);
+    }
+
+    #[test]
+    fn hover_renders_in_the_background_without_touching_the_project() {
+        render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let url = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        // Unique source, so no earlier run has cached it
+        let code = format!("flowchart TD\n    A --> B%% {}", dir.path().display());
+        let documents = HashMap::from([(url.to_string(), format!("# Doc\n\n```mermaid\n{}\n```\n", code))]);
+
+        let params = HoverParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: url },
+                position: Position { line: 3, character: 0 },
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let hover_text = || match get_hover(&params, &documents).unwrap().unwrap().contents {
+            HoverContents::Markup(markup) => markup.value,
+            other => panic!("unexpected hover contents: {:?}", other),
+        };
+
+        assert!(hover_text().contains("Rendering Mermaid diagram"));
+
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
+        let mut text = hover_text();
+        while !text.starts_with("![") && Instant::now() < deadline {
+            thread::sleep(std::time::Duration::from_millis(20));
+            text = hover_text();
+        }
+        assert!(text.contains(HOVER_MEDIA_DIR), "hover was: {}", text);
+        assert!(!dir.path().join(MERMAID_MEDIA_DIR).exists());
+    }
+
+    #[test]
+    fn refreshes_the_preview_in_the_background() {
+        render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let url = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        // An existing preview is refreshed on every save
+        fs::write(dir.path().join("doc.preview.md"), "").unwrap();
+
+        let save_jobs: SaveJobs = Jobs::spawn(SaveJob::key, run_save_job);
+        refresh_preview_on_save(&save_jobs, &url, "```mermaid\npie\n```\n");
+        let outcome = save_jobs.results().recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+
+        assert!(matches!(outcome, SaveOutcome::Done));
+        let preview = fs::read_to_string(dir.path().join("doc.preview.md")).unwrap();
+        assert!(preview.contains(".mermaid/preview/doc.md/"), "preview was: {}", preview);
+    }
+
+    #[test]
+    fn render_action_writes_nothing_until_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("flow.mmd")).unwrap();
+        let content = "flowchart TD\n    A --> B";
+        let documents = HashMap::from([(uri.to_string(), content.to_string())]);
+        let params: CodeActionParams = serde_json::from_value(json!({
+            "textDocument": { "uri": uri },
+            "range": { "start": { "line": 1, "character": 0 }, "end": { "line": 1, "character": 0 } },
+            "context": { "diagnostics": [] }
+        }))
+        .unwrap();
+        let (server, _client) = Connection::memory();
+
+        let actions = get_code_actions(&params, &documents, &server).unwrap();
+        let action = actions.iter().find(|action| action.title == "Render Mermaid Diagram").unwrap();
+        assert!(action.edit.is_none());
+        let command = action.command.as_ref().unwrap();
+        assert_eq!(command.command, "mermaid.renderSingle");
+        let args = &command.arguments.as_ref().unwrap()[0];
+        assert_eq!(args["endLine"], 1);
+        assert_eq!(args["endCharacter"], "    A --> B".len());
+        assert_eq!(args["code"], content);
+        assert!(!dir.path().join(MERMAID_MEDIA_DIR).exists());
+    }
+
+    #[test]
+    fn applies_diagrams_rendered_on_save_only_to_unchanged_documents() {
+        render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap().to_string();
+        let content = "# Doc\n\n```mermaid\npie\n```\n";
+        let (server, client) = Connection::memory();
+        let mut pending_requests = PendingRequests::default();
+
+        let render = || match run_save_job(SaveJob::Render { uri: uri.clone(), content: content.to_string() }) {
+            SaveOutcome::Rendered(rendered) => rendered,
+            _ => panic!("expected rendered diagrams"),
+        };
+        assert!(render().failures.is_empty());
+
+        // Edited while rendering: the edit no longer fits
+        let edited = HashMap::from([(uri.clone(), format!("{}More\n", content))]);
+        apply_rendered_on_save(&server, &mut pending_requests, &edited, render()).unwrap();
+        assert!(client.receiver.try_recv().is_err());
+
+        let unchanged = HashMap::from([(uri.clone(), content.to_string())]);
+        apply_rendered_on_save(&server, &mut pending_requests, &unchanged, render()).unwrap();
+        match client.receiver.try_recv().unwrap() {
+            Message::Request(request) => assert_eq!(request.method, "workspace/applyEdit"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn syncs_sidecars_in_the_background() {
+        render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("doc_0_abc.mmd");
+        fs::write(&source, "pie").unwrap();
+        fs::write(dir.path().join("doc_0_abc.svg"), "<svg/>").unwrap();
+
+        let save_jobs: SaveJobs = Jobs::spawn(SaveJob::key, run_save_job);
+        save_jobs.queue(SaveJob::Sidecar { source });
+        let outcome = save_jobs.results().recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+
+        assert!(matches!(outcome, SaveOutcome::Done));
+        let svg = fs::read_to_string(dir.path().join("doc_0_abc.svg")).unwrap();
+        assert_eq!(recorded_source_hash(&svg), Some(content_hash("pie").as_str()));
+    }
+
+    #[test]
+    fn outgoing_requests_get_unique_ids() {
+        let (server, client) = Connection::memory();
+        let mut pending_requests = PendingRequests::default();
+        let other = || PendingRequest::Other { method: "window/showDocument".to_string() };
+
+        let first = pending_requests.send(&server, "window/showDocument", json!({}), other()).unwrap();
+        let second = pending_requests.send(&server, "window/showDocument", json!({}), other()).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(client.receiver.try_iter().count(), 2);
+
+        assert!(pending_requests.complete(&first).is_some());
+        assert!(pending_requests.complete(&first).is_none());
+    }
+
+    #[test]
+    fn reads_whether_an_edit_was_applied() {
+        let response = |result| Response { id: RequestId::from(1), result: Some(result), error: None };
+        assert_eq!(edit_outcome(&response(json!({ "applied": true }))), Ok(()));
+        assert_eq!(
+            edit_outcome(&response(json!({ "applied": false, "failureReason": "document changed" }))),
+            Err("document changed".to_string())
+        );
+        assert!(edit_outcome(&response(json!(null))).is_err());
+
+        let failed = Response::new_err(RequestId::from(2), -32603, "internal".to_string());
+        assert_eq!(edit_outcome(&failed), Err("internal".to_string()));
+    }
+
+    #[test]
+    fn applied_render_keeps_an_unsaved_neighbours_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_dir = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir(&media_dir).unwrap();
+        for name in ["doc_0_aaaaaaaaaaaa.svg", "doc_0_bbbbbbbbbbbb.svg", "doc_v2_0_abcdefabcdef.svg"] {
+            fs::write(media_dir.join(name), "<svg/>").unwrap();
+        }
+
+        // doc_v2.md is open with a rendered diagram but has never been saved
+        let v2_uri = Url::from_file_path(dir.path().join("doc_v2.md")).unwrap();
+        let documents = HashMap::from([(
+            v2_uri.to_string(),
+            "![Mermaid Diagram](.mermaid/doc_v2_0_abcdefabcdef.svg)\n".to_string(),
+        )]);
+
+        // Rendering doc.md replaced its link to the old image
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let content = "![Mermaid Diagram](.mermaid/doc_0_bbbbbbbbbbbb.svg)\n";
+        let edit = TextEdit {
+            range: Range::new(Position::new(0, 28), Position::new(0, 50)),
+            new_text: "doc_0_aaaaaaaaaaaa.svg".to_string(),
+        };
+        let changes = HashMap::from([(uri.clone(), vec![edit])]);
+        let cleanup = PendingCleanup::after_edit(uri.as_str(), content, &changes);
+
+        let (server, _client) = Connection::memory();
+        let mut pending_requests = PendingRequests::default();
+        let pending = PendingRequest::ApplyEdit { label: "Render".to_string(), cleanup };
+        let id = pending_requests.send(&server, "workspace/applyEdit", json!({}), pending).unwrap();
+        let response = Response { id, result: Some(json!({ "applied": true })), error: None };
+        handle_response(&server, response, &mut pending_requests, &documents);
+
+        assert!(media_dir.join("doc_0_aaaaaaaaaaaa.svg").exists());
+        assert!(!media_dir.join("doc_0_bbbbbbbbbbbb.svg").exists());
+        assert!(media_dir.join("doc_v2_0_abcdefabcdef.svg").exists());
+    }
+
+    #[test]
+    fn clears_the_shared_cache_only_once_confirmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = cache::cache_path(dir.path(), "abc", "svg");
+        fs::create_dir_all(entry.parent().unwrap()).unwrap();
+        fs::write(&entry, "<svg/>").unwrap();
+
+        let (server, client) = Connection::memory();
+        let mut pending_requests = PendingRequests::default();
+        let ask = |pending_requests: &mut PendingRequests| {
+            confirm_clear_shared_cache(&server, pending_requests, vec![dir.path().to_path_buf()]).unwrap();
+            match client.receiver.try_recv().unwrap() {
+                Message::Request(request) => {
+                    assert_eq!(request.method, "window/showMessageRequest");
+                    request.id
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        };
+
+        let id = ask(&mut pending_requests);
+        let response = Response { id, result: Some(json!(null)), error: None };
+        handle_response(&server, response, &mut pending_requests, &HashMap::new());
+        assert!(entry.exists());
+
+        let id = ask(&mut pending_requests);
+        let response = Response { id, result: Some(json!({ "title": CLEAR_SHARED_CACHE_ACTION })), error: None };
+        handle_response(&server, response, &mut pending_requests, &HashMap::new());
+        assert!(!entry.exists());
+    }
+
+    #[test]
+    fn renders_when_the_cache_entry_cannot_be_read() {
+        render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let options = RenderOptions::default();
+        // e.g. evicted by another server between lookup and read
+        fs::create_dir_all(cache_path_for("pie", &options, dir.path(), ImageFormat::Svg)).unwrap();
+
+        let (_, contents) = render_options_to_cache("pie", &options, dir.path(), ImageFormat::Svg).unwrap();
+        assert!(String::from_utf8(contents).unwrap().contains("test"));
+    }
+
+    #[test]
+    fn recognises_output_names_by_document_stem() {
+        assert!(is_output_name_for("doc_0_0123456789ab.svg", "doc"));
+        assert!(is_output_name_for("doc_login-flow_0123456789ab.mmd", "doc"));
+        assert!(is_output_name_for("doc_0-dark_0123456789ab.png", "doc"));
+        assert!(!is_output_name_for("doc_0_0123456789ab.pdf", "doc"));
+        assert!(!is_output_name_for("doc_0_notahash.svg", "doc"));
+        assert!(!is_output_name_for("docs_0_0123456789ab.svg", "doc"));
+        assert!(!is_output_name_for("doc_0123456789ab.svg", "doc"));
+    }
+
+    #[test]
+    fn cleanup_only_removes_this_documents_stale_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_dir = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir(&media_dir).unwrap();
+        let files = [
+            "doc_0_aaaaaaaaaaaa.svg",
+            "doc_0_bbbbbbbbbbbb.svg",
+            "doc_1_cccccccccccc.svg",
+            "other_0_dddddddddddd.svg",
+            "doc_v2_0_abcdefabcdef.svg",
+            "doc_v2_0_eeeeeeeeeeee.svg",
+        ];
+        for name in files {
+            fs::write(media_dir.join(name), "<svg/>").unwrap();
+        }
+        // A sibling document still links one of this document's old renders
+        fs::write(dir.path().join("copy.md"), "![Mermaid Diagram](.mermaid/doc_1_cccccccccccc.svg)\n").unwrap();
+        // doc_v2.md exists on disk without diagrams; its render has been applied but not saved
+        let v2 = dir.path().join("doc_v2.md");
+        fs::write(&v2, "```mermaid\npie\n```\n").unwrap();
+        let open_documents = HashMap::from([(
+            Url::from_file_path(&v2).unwrap().to_string(),
+            "![Mermaid Diagram](.mermaid/doc_v2_0_abcdefabcdef.svg)\n".to_string(),
+        )]);
+
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap().to_string();
+        let content = "![Mermaid Diagram](.mermaid/doc_0_aaaaaaaaaaaa.svg)\n";
+        cleanup_old_diagram_files(&uri, content, &media_dir, &open_documents).unwrap();
+
+        assert!(media_dir.join("doc_0_aaaaaaaaaaaa.svg").exists());
+        assert!(!media_dir.join("doc_0_bbbbbbbbbbbb.svg").exists());
+        assert!(media_dir.join("doc_1_cccccccccccc.svg").exists());
+        assert!(media_dir.join("other_0_dddddddddddd.svg").exists());
+        // doc_v2's files are never doc's to remove, referenced or not
+        assert!(media_dir.join("doc_v2_0_abcdefabcdef.svg").exists());
+        assert!(media_dir.join("doc_v2_0_eeeeeeeeeeee.svg").exists());
+    }
+
+    #[test]
+    fn output_names_are_deterministic() {
+        let document = Path::new("/docs/guide.md");
+        let first = output_base_name(document, "0", "pie");
+        assert_eq!(first, output_base_name(document, "0", "pie"));
+        assert!(first.starts_with("guide_0_"));
+        assert_ne!(first, output_base_name(document, "1", "pie"));
+    }
+}
@@ -0,0 +1,66 @@
+// Long-lived Mermaid renderer used by mermaid-lsp's "worker" backend.
+//
+// Launches headless Chromium once and renders every diagram in the same browser.
+// Protocol (one JSON object per line):
+//   stdin:  {"id": 1, "code": "flowchart TD ...", "config": {...}, "background": "white",
+//            "format": "svg" | "png" | "pdf", "scale": 1}
+//   stdout: {"id": 1, "svg": "<svg ...>"}, {"id": 1, "data": "<base64 png/pdf>"}  or  {"id": 1, "error": "message"}
+// The first line written to stdout is {"ready": true} once the browser is up.
+
+import { createRequire } from "node:module";
+import { join } from "node:path";
+import { createInterface } from "node:readline";
+import { pathToFileURL } from "node:url";
+
+const cliDir = process.env.MERMAID_CLI_DIR;
+if (!cliDir) {
+  process.stderr.write("MERMAID_CLI_DIR is not set\n");
+  process.exit(1);
+}
+
+const { renderMermaid } = await import(pathToFileURL(join(cliDir, "src", "index.js")).href);
+const puppeteer = createRequire(join(cliDir, "package.json"))("puppeteer");
+
+const browser = await puppeteer.launch({ headless: "new" });
+const decoder = new TextDecoder();
+
+const send = (message) => process.stdout.write(JSON.stringify(message) + "\n");
+
+// Render one request at a time so responses are written in request order
+let queue = Promise.resolve();
+
+createInterface({ input: process.stdin }).on("line", (line) => {
+  queue = queue.then(async () => {
+    let request;
+    try {
+      request = JSON.parse(line);
+    } catch (err) {
+      send({ id: null, error: `invalid request: ${err.message}` });
+      return;
+    }
+
+    try {
+      const format = ["png", "pdf"].includes(request.format) ? request.format : "svg";
+      const { data } = await renderMermaid(browser, request.code, format, {
+        backgroundColor: request.background ?? "white",
+        mermaidConfig: request.config ?? {},
+        // Same default viewport as mmdc; the scale factor sets the PNG resolution
+        viewport: { width: 800, height: 600, deviceScaleFactor: request.scale ?? 1 },
+        pdfFit: true,
+      });
+      if (format === "svg") {
+        send({ id: request.id, svg: decoder.decode(data) });
+      } else {
+        send({ id: request.id, data: Buffer.from(data).toString("base64") });
+      }
+    } catch (err) {
+      send({ id: request.id, error: String(err?.message ?? err) });
+    }
+  });
+}).on("close", async () => {
+  await queue;
+  await browser.close();
+  process.exit(0);
+});
+
+send({ ready: true });
@@ -0,0 +1,204 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::diagnostics::find_diagram_sources;
+use crate::directives::diagram_label;
+use crate::{markdown_image, media_dir_for, render_to_cache, write_if_changed};
+
+/// Suffix appended to a document's stem to name its companion preview.
+pub const PREVIEW_SUFFIX: &str = ".preview.md";
+
+/// Directory inside `.mermaid` holding the images companion previews link to.
+pub const PREVIEW_DIR: &str = "preview";
+
+/// Path of the companion preview file for a document (`<doc>.preview.md`).
+pub fn preview_path(document: &Path) -> Option<PathBuf> {
+    let stem = document.file_stem()?.to_string_lossy();
+    if is_preview_file(document) {
+        return None;
+    }
+    Some(document.with_file_name(format!("{}{}", stem, PREVIEW_SUFFIX)))
+}
+
+/// Whether the path is itself a generated preview file.
+pub fn is_preview_file(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().ends_with(PREVIEW_SUFFIX))
+        .unwrap_or(false)
+}
+
+/// Build the preview Markdown: the document with every diagram replaced by its image.
+///
+/// The source document is not modified. Rendered images are copied out of the render cache
+/// into `.mermaid/preview/<document>/`, so pruning or clearing the cache never breaks an open
+/// preview; images the preview no longer uses are removed from there.
+pub fn render_preview_content(document: &Path, content: &str, is_mermaid_document: bool) -> Result<String> {
+    let media_dir = media_dir_for(document)?;
+    let parent = document.parent().unwrap_or_else(|| Path::new("."));
+    let images_dir = preview_images_dir(&media_dir, document);
+    fs::create_dir_all(&images_dir)
+        .map_err(|e| anyhow!("Failed to create preview image directory: {}", e))?;
+    let mut used_images = HashSet::new();
+    let lines: Vec<&str> = content.lines().collect();
+    let sources = find_diagram_sources(content, is_mermaid_document);
+
+    let name = document
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut output = format!(
+        "<!-- Generated by Mermaid Preview from {}. Changes to this file are overwritten on save. -->\n\n",
+        name
+    );
+
+    let mut next_line = 0;
+    for source in &sources {
+        let fence_end = if is_mermaid_document {
+            lines.len()
+        } else {
+            source.closing_line + 1
+        };
+
+        if !is_mermaid_document {
+            for line in &lines[next_line..source.fence_line] {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+
+        match render_to_cache(&source.code, &media_dir)
+            .and_then(|(cache_path, svg)| copy_preview_image(&images_dir, &cache_path, &svg))
+        {
+            Ok(image_path) => {
+                used_images.insert(image_path.clone());
+                let relative = image_path.strip_prefix(parent).unwrap_or(&image_path);
+                let image = markdown_image(&diagram_label(&source.code), &relative.to_string_lossy());
+                output.push_str(&format!("{}\n", image));
+            }
+            Err(e) => {
+                warn!("Preview render failed at line {}: {}", source.fence_line + 1, e);
+                // Keep the source visible so the preview still shows what failed
+                for line in &lines[source.fence_line..fence_end] {
+                    output.push_str(line);
+                    output.push('\n');
+                }
+                output.push_str(&format!("\n> ⚠️ Mermaid render failed: {}\n", first_line(&e.to_string())));
+            }
+        }
+
+        next_line = fence_end;
+    }
+
+    if !is_mermaid_document && next_line < lines.len() {
+        for line in &lines[next_line..] {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    remove_unused_images(&images_dir, &used_images);
+    Ok(output)
+}
+
+/// `.mermaid/preview/<document file name>/`: one directory per document, so pruning one
+/// preview's images never touches another's.
+fn preview_images_dir(media_dir: &Path, document: &Path) -> PathBuf {
+    let name = document.file_name().unwrap_or_default();
+    media_dir.join(PREVIEW_DIR).join(name)
+}
+
+/// Copy a cached render into the preview's directory, named like its cache entry.
+fn copy_preview_image(images_dir: &Path, cache_path: &Path, svg: &str) -> Result<PathBuf> {
+    let name = cache_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid cache path: {:?}", cache_path))?;
+    let image_path = images_dir.join(name);
+    write_if_changed(&image_path, svg.as_bytes())
+        .map_err(|e| anyhow!("Failed to write preview image {:?}: {}", image_path, e))?;
+    Ok(image_path)
+}
+
+fn remove_unused_images(images_dir: &Path, used_images: &HashSet<PathBuf>) {
+    let Ok(entries) = fs::read_dir(images_dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.is_file() && !used_images.contains(&path) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to remove unused preview image {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Regenerate the companion preview for a document and return its path.
+pub fn write_preview(document: &Path, content: &str, is_mermaid_document: bool) -> Result<PathBuf> {
+    let target = preview_path(document)
+        .ok_or_else(|| anyhow!("No preview available for {:?}", document))?;
+    let preview = render_preview_content(document, content, is_mermaid_document)?;
+
+    // Skip the write when nothing changed to avoid needless reloads in the editor
+    if fs::read_to_string(&target).map(|existing| existing == preview).unwrap_or(false) {
+        return Ok(target);
+    }
+
+    fs::write(&target, preview).map_err(|e| anyhow!("Failed to write preview {:?}: {}", target, e))?;
+    info!("Wrote Mermaid preview to {:?}", target);
+    Ok(target)
+}
+
+fn first_line(message: &str) -> &str {
+    message.lines().next().unwrap_or(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_path_sits_next_to_document() {
+        assert_eq!(
+            preview_path(Path::new("/docs/guide.md")),
+            Some(PathBuf::from("/docs/guide.preview.md"))
+        );
+        assert_eq!(
+            preview_path(Path::new("/docs/flow.mmd")),
+            Some(PathBuf::from("/docs/flow.preview.md"))
+        );
+    }
+
+    #[test]
+    fn preview_images_survive_cache_clearing() {
+        crate::render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let document = dir.path().join("guide.md");
+        let content = "# Guide\n\n```mermaid\npie\n```\n\n```mermaid\nflowchart TD\n```\n";
+
+        let preview = render_preview_content(&document, content, false).unwrap();
+        let images: Vec<&str> = preview
+            .lines()
+            .filter_map(|line| line.split_once("](").map(|(_, rest)| rest.trim_end_matches(')')))
+            .collect();
+        assert_eq!(images.len(), 2);
+        assert!(images.iter().all(|image| image.starts_with(".mermaid/preview/guide.md/")));
+
+        crate::cache::clear_cache(&dir.path().join(".mermaid")).unwrap();
+        assert!(images.iter().all(|image| dir.path().join(image).is_file()));
+
+        // Images of diagrams no longer in the document are removed
+        render_preview_content(&document, "# Guide\n\n```mermaid\npie\n```\n", false).unwrap();
+        let remaining = fs::read_dir(dir.path().join(".mermaid/preview/guide.md")).unwrap().count();
+        assert_eq!(remaining, 1);
+    }
+
+    #[test]
+    fn previews_never_get_previews_of_their_own() {
+        assert!(is_preview_file(Path::new("/docs/guide.preview.md")));
+        assert_eq!(preview_path(Path::new("/docs/guide.preview.md")), None);
+    }
+}
@@ -3,12 +3,27 @@ use std::{
     env, fs,
     path::PathBuf,
     process::{Command, Stdio},
+    sync::{Arc, RwLock},
 };
 use tempfile::tempdir;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use html_escape;
 
+use crate::worker::WorkerRenderer;
+
+/// Version of the [`sanitize_svg`] output. Bump it whenever sanitization changes so
+/// previously cached renders are not served again.
+pub const SANITIZER_VERSION: u32 = 1;
+
+/// Magic bytes every PNG file starts with.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Magic bytes every PDF file starts with.
+const PDF_SIGNATURE: &[u8] = b"%PDF-";
+
+/// Mermaid configuration embedded into the binary; user settings are layered on top.
+pub const DEFAULT_MERMAID_CONFIG: &str = include_str!("mermaid-config.json");
+
 // Precompiled regex patterns to avoid DoS and improve performance
 static FOREIGN_OBJECT_REGEX: Lazy<Regex> = Lazy::new(|| {
     // Safe pattern using negated character classes to prevent catastrophic backtracking:
@@ -22,45 +37,131 @@ static FOREIGN_OBJECT_REGEX: Lazy<Regex> = Lazy::new(|| {
         .expect("Foreign object regex should compile")
 });
 
-/// Render Mermaid code to SVG using mmdc and sanitize the output.
-///
-/// # Security Considerations
-///
-/// This function assumes the Mermaid code comes from a trusted source (user's own files).
-/// It provides the following protections:
-///
-/// - **No Command Injection**: Uses `Command::new()` with explicit arguments, not shell execution
-/// - **Isolated Execution**: Writes to temporary files in system temp directory
-/// - **Script Tag Removal**: Rejects SVGs containing `<script>` tags
-/// - **ForeignObject Sanitization**: Converts foreignObject elements to native SVG text
-///
-/// However, it cannot protect against:
-/// - Vulnerabilities in the mmdc binary itself
-/// - Malicious Mermaid syntax that exploits mmdc parser bugs
-/// - Resource exhaustion from extremely complex diagrams
+/// Renderer used by [`render_mermaid`]; replaced at startup from the server settings.
+static ACTIVE_RENDERER: Lazy<RwLock<Arc<dyn Renderer>>> =
+    Lazy::new(|| RwLock::new(Arc::new(MmdcRenderer)));
+
+/// Options handed to a [`Renderer`] for a single diagram.
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    /// Mermaid configuration as a JSON document.
+    pub config: String,
+    /// Background colour of the rendered diagram.
+    pub background: String,
+    /// Device scale factor for raster output (PNG only).
+    pub scale: f64,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            config: DEFAULT_MERMAID_CONFIG.to_string(),
+            background: "white".to_string(),
+            scale: 1.0,
+        }
+    }
+}
+
+/// Image format a diagram can be rendered to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Svg,
+    Png,
+    Pdf,
+}
+
+impl ImageFormat {
+    /// File extension, also used as the format name in cache keys.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
+            ImageFormat::Pdf => "pdf",
+        }
+    }
+
+    /// MIME type, as used in `data:` URIs.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Pdf => "application/pdf",
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Short hash identifying this configuration, as reported by `--version`.
+    pub fn config_hash(&self) -> String {
+        crate::content_hash(&format!("{}\n{}", self.config, self.background))
+    }
+}
+
+/// A backend that turns Mermaid source into raw SVG.
 ///
-/// See SECURITY.md for complete security documentation.
-pub fn render_mermaid(mermaid_code: &str) -> Result<String> {
-    if mermaid_code.trim().is_empty() {
-        return Err(anyhow!("Mermaid code is empty"));
+/// Implementations only produce the SVG; sanitization is always applied afterwards
+/// by [`render_mermaid`], so a backend never has to be trusted with that step.
+pub trait Renderer: Send + Sync {
+    /// Short identifier used in settings and log output (e.g. `"mmdc"`).
+    fn name(&self) -> &'static str;
+
+    /// Render `mermaid_code` to an unsanitized SVG document.
+    fn render_svg(&self, mermaid_code: &str, options: &RenderOptions) -> Result<String>;
+
+    /// Render `mermaid_code` to PNG bytes, honouring `options.scale`.
+    fn render_png(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<Vec<u8>> {
+        Err(anyhow!("The '{}' renderer cannot produce PNG output", self.name()))
     }
 
+    /// Render `mermaid_code` to a single-page PDF sized to fit the diagram.
+    fn render_pdf(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<Vec<u8>> {
+        Err(anyhow!("The '{}' renderer cannot produce PDF output", self.name()))
+    }
+
+    /// Release any long-lived resources (called when the server shuts down).
+    fn shutdown(&self) {}
+}
+
+/// Renders each diagram by spawning the Mermaid CLI (`mmdc`).
+pub struct MmdcRenderer;
+
+impl Renderer for MmdcRenderer {
+    fn name(&self) -> &'static str {
+        "mmdc"
+    }
+
+    fn render_svg(&self, mermaid_code: &str, options: &RenderOptions) -> Result<String> {
+        let output = run_mmdc(mermaid_code, options, ImageFormat::Svg)?;
+        String::from_utf8(output).map_err(|e| anyhow!("Failed to read SVG output: {}", e))
+    }
+
+    fn render_png(&self, mermaid_code: &str, options: &RenderOptions) -> Result<Vec<u8>> {
+        run_mmdc(mermaid_code, options, ImageFormat::Png)
+    }
+
+    fn render_pdf(&self, mermaid_code: &str, options: &RenderOptions) -> Result<Vec<u8>> {
+        run_mmdc(mermaid_code, options, ImageFormat::Pdf)
+    }
+}
+
+fn run_mmdc(mermaid_code: &str, options: &RenderOptions, format: ImageFormat) -> Result<Vec<u8>> {
     let mmdc_path = mmdc_path()?;
 
     let temp_dir = tempdir().map_err(|e| anyhow!("Failed to create temp dir: {}", e))?;
     let input_path = temp_dir.path().join("diagram.mmd");
-    let output_path = temp_dir.path().join("diagram.svg");
+    let output_path = temp_dir.path().join(format!("diagram.{}", format.extension()));
     let config_path = temp_dir.path().join("mermaid-config.json");
 
     // Write mermaid code and config
     fs::write(&input_path, mermaid_code)
         .map_err(|e| anyhow!("Failed to write temp Mermaid file: {}", e))?;
 
-    fs::write(&config_path, include_str!("mermaid-config.json"))
+    fs::write(&config_path, &options.config)
         .map_err(|e| anyhow!("Failed to write temp config file: {}", e))?;
 
     // Run mmdc with configuration file for htmlLabels: false
-    let output = Command::new(&mmdc_path)
+    let mut command = Command::new(&mmdc_path);
+    command
         .arg("-i")
         .arg(&input_path)
         .arg("-o")
@@ -68,7 +169,19 @@ pub fn render_mermaid(mermaid_code: &str) -> Result<String> {
         .arg("-c")
         .arg(&config_path)
         .arg("-b")
-        .arg("white")
+        .arg(&options.background);
+    match format {
+        ImageFormat::Svg => {}
+        ImageFormat::Png => {
+            command.arg("-s").arg(options.scale.to_string());
+        }
+        // Size the page to the diagram instead of A4
+        ImageFormat::Pdf => {
+            command.arg("--pdfFit");
+        }
+    }
+
+    let output = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()
@@ -79,8 +192,142 @@ pub fn render_mermaid(mermaid_code: &str) -> Result<String> {
         return Err(anyhow!("mmdc error: {}", stderr.trim()));
     }
 
-    let svg_contents = fs::read_to_string(&output_path)
-        .map_err(|e| anyhow!("Failed to read SVG output: {}", e))?;
+    fs::read(&output_path)
+        .map_err(|e| anyhow!("Failed to read {} output: {}", format.extension().to_uppercase(), e))
+}
+
+/// Look up a renderer backend by the name used in the `renderer` setting.
+pub fn renderer_from_name(name: &str) -> Result<Arc<dyn Renderer>> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "" | "mmdc" => Ok(Arc::new(MmdcRenderer)),
+        "worker" => Ok(Arc::new(WorkerRenderer::new())),
+        other => Err(anyhow!("Unknown renderer '{}' (available: mmdc, worker)", other)),
+    }
+}
+
+/// Replace the renderer used by [`render_mermaid`].
+pub fn set_renderer(renderer: Arc<dyn Renderer>) {
+    match ACTIVE_RENDERER.write() {
+        Ok(mut active) => *active = renderer,
+        Err(poisoned) => *poisoned.into_inner() = renderer,
+    }
+}
+
+/// The renderer currently used by [`render_mermaid`].
+pub fn active_renderer() -> Arc<dyn Renderer> {
+    match ACTIVE_RENDERER.read() {
+        Ok(active) => Arc::clone(&active),
+        Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+    }
+}
+
+/// Backend for tests elsewhere in the crate: a fixed SVG, and outputs with valid PNG/PDF signatures.
+#[cfg(test)]
+pub struct TestRenderer;
+
+#[cfg(test)]
+impl Renderer for TestRenderer {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    fn render_svg(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<String> {
+        Ok("<svg><text>test</text></svg>".to_string())
+    }
+
+    fn render_png(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<Vec<u8>> {
+        // The smallest well-formed chunk layout: IHDR for a 1x1 image, then IEND (CRCs unchecked)
+        let ihdr = b"\0\0\0\x0dIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0\0\0\0\0";
+        let iend = b"\0\0\0\0IEND\0\0\0\0";
+        Ok([PNG_SIGNATURE, ihdr, iend].concat())
+    }
+
+    fn render_pdf(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<Vec<u8>> {
+        Ok([PDF_SIGNATURE, b"1.5 test"].concat())
+    }
+}
+
+/// Make [`TestRenderer`] the active renderer, so tests can render without mmdc.
+#[cfg(test)]
+pub fn use_test_renderer() {
+    set_renderer(Arc::new(TestRenderer));
+}
+
+/// Render Mermaid code to SVG using the active [`Renderer`] and sanitize the output.
+///
+/// # Security Considerations
+///
+/// This function assumes the Mermaid code comes from a trusted source (user's own files).
+/// It provides the following protections:
+///
+/// - **No Command Injection**: Uses `Command::new()` with explicit arguments, not shell execution
+/// - **Isolated Execution**: The mmdc backend writes to temporary files in system temp directory
+/// - **Script Tag Removal**: Rejects SVGs containing `<script>` tags
+/// - **ForeignObject Sanitization**: Converts foreignObject elements to native SVG text
+///
+/// However, it cannot protect against:
+/// - Vulnerabilities in the mmdc binary itself
+/// - Malicious Mermaid syntax that exploits mmdc parser bugs
+/// - Resource exhaustion from extremely complex diagrams
+///
+/// See SECURITY.md for complete security documentation.
+pub fn render_mermaid(mermaid_code: &str) -> Result<String> {
+    let options = crate::config::current().render_options_for(mermaid_code);
+    render_with(active_renderer().as_ref(), mermaid_code, &options)
+}
+
+/// Render a diagram in any format with the active renderer and explicit options.
+pub fn render_mermaid_format_with(
+    mermaid_code: &str,
+    options: &RenderOptions,
+    format: ImageFormat,
+) -> Result<Vec<u8>> {
+    let renderer = active_renderer();
+    match format {
+        ImageFormat::Svg => render_with(renderer.as_ref(), mermaid_code, options).map(String::into_bytes),
+        ImageFormat::Png => render_binary_with(renderer.as_ref(), mermaid_code, options, format),
+        ImageFormat::Pdf => render_binary_with(renderer.as_ref(), mermaid_code, options, format),
+    }
+}
+
+/// Render PNG or PDF with an explicit backend, checking the output really is that format.
+pub fn render_binary_with(
+    renderer: &dyn Renderer,
+    mermaid_code: &str,
+    options: &RenderOptions,
+    format: ImageFormat,
+) -> Result<Vec<u8>> {
+    if mermaid_code.trim().is_empty() {
+        return Err(anyhow!("Empty mermaid code"));
+    }
+
+    let (output, signature) = match format {
+        ImageFormat::Png => (renderer.render_png(mermaid_code, options)?, PNG_SIGNATURE),
+        ImageFormat::Pdf => (renderer.render_pdf(mermaid_code, options)?, PDF_SIGNATURE),
+        ImageFormat::Svg => return Err(anyhow!("SVG output is text; use render_with")),
+    };
+    if !output.starts_with(signature) {
+        return Err(anyhow!(
+            "Renderer '{}' returned invalid {} output",
+            renderer.name(),
+            format.extension().to_uppercase()
+        ));
+    }
+
+    Ok(output)
+}
+
+/// Render with an explicit backend and sanitize its output.
+pub fn render_with(
+    renderer: &dyn Renderer,
+    mermaid_code: &str,
+    options: &RenderOptions,
+) -> Result<String> {
+    if mermaid_code.trim().is_empty() {
+        return Err(anyhow!("Mermaid code is empty"));
+    }
+
+    let svg_contents = renderer.render_svg(mermaid_code, options)?;
 
     let sanitized = sanitize_svg(&svg_contents)?;
 
@@ -178,7 +425,24 @@ fn extract_attr(tag: &str, attr: &str) -> Option<String> {
     attr_regex.captures(tag).map(|c| c[1].to_string())
 }
 
-fn mmdc_path() -> Result<PathBuf> {
+/// Version reported by `mmdc --version`.
+pub fn mmdc_version() -> Result<String> {
+    let mmdc_path = mmdc_path()?;
+    let output = Command::new(&mmdc_path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("Failed to execute mmdc: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("mmdc --version failed: {}", stderr.trim()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub(crate) fn mmdc_path() -> Result<PathBuf> {
     // First check for MMDC_PATH environment variable
     if let Ok(path) = env::var("MMDC_PATH") {
         let candidate = PathBuf::from(&path);
@@ -218,6 +482,74 @@ static HTML_TAG_REGEX: Lazy<Regex> = Lazy::new(|| {
 mod tests {
     use super::*;
 
+    /// Backend that returns a canned SVG, so rendering can be tested without mmdc.
+    struct StubRenderer(&'static str);
+
+    impl Renderer for StubRenderer {
+        fn name(&self) -> &'static str {
+            "stub"
+        }
+
+        fn render_svg(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    struct PngStub(&'static [u8]);
+
+    impl Renderer for PngStub {
+        fn name(&self) -> &'static str {
+            "png-stub"
+        }
+
+        fn render_svg(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<String> {
+            Err(anyhow!("svg not supported"))
+        }
+
+        fn render_png(&self, _mermaid_code: &str, _options: &RenderOptions) -> Result<Vec<u8>> {
+            Ok(self.0.to_vec())
+        }
+    }
+
+    #[test]
+    fn binary_output_must_match_its_format() {
+        let options = RenderOptions::default();
+        let png = PngStub(b"\x89PNG\r\n\x1a\n\0\0");
+        assert!(render_binary_with(&png, "pie", &options, ImageFormat::Png).is_ok());
+
+        let error = render_binary_with(&PngStub(b"<svg/>"), "pie", &options, ImageFormat::Png).unwrap_err();
+        assert!(error.to_string().contains("invalid PNG"));
+
+        // Backends without PNG or PDF support say so
+        let svg_only = StubRenderer("<svg/>");
+        let error = render_binary_with(&svg_only, "pie", &options, ImageFormat::Png).unwrap_err();
+        assert!(error.to_string().contains("cannot produce PNG"));
+        let error = render_binary_with(&svg_only, "pie", &options, ImageFormat::Pdf).unwrap_err();
+        assert!(error.to_string().contains("cannot produce PDF"));
+    }
+
+    #[test]
+    fn render_with_sanitizes_backend_output() {
+        let renderer = StubRenderer("<svg><rect onclick=\"alert()\" width=\"10\" /></svg>");
+        let svg = render_with(&renderer, "flowchart TD\n    A --> B", &RenderOptions::default()).unwrap();
+        assert!(svg.contains("<rect"));
+        assert!(!svg.contains("onclick"));
+    }
+
+    #[test]
+    fn render_with_rejects_empty_code() {
+        let renderer = StubRenderer("<svg></svg>");
+        assert!(render_with(&renderer, "   \n", &RenderOptions::default()).is_err());
+    }
+
+    #[test]
+    fn resolves_renderers_by_name() {
+        assert_eq!(renderer_from_name("mmdc").unwrap().name(), "mmdc");
+        assert_eq!(renderer_from_name("").unwrap().name(), "mmdc");
+        assert_eq!(renderer_from_name("Worker").unwrap().name(), "worker");
+        assert!(renderer_from_name("does-not-exist").is_err());
+    }
+
     #[test]
     fn rejects_scripts() {
         let svg = "<svg><script>alert('xss')</script></svg>";
@@ -0,0 +1,238 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use lsp_server::Connection;
+use lsp_types::{
+    DidChangeWatchedFilesRegistrationOptions, FileChangeType, FileEvent, FileSystemWatcher,
+    GlobPattern, Registration, RegistrationParams, WatchKind,
+};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config;
+use crate::render::ImageFormat;
+use crate::{
+    content_hash, recorded_image_source_hash, render_options_to_cache, stamp_png_source_hash, stamp_source_hash,
+    PendingRequest, PendingRequests, MERMAID_FILE_EXTENSION, MERMAID_MEDIA_DIR,
+};
+
+/// Files watched so editing a sidecar source refreshes its rendered image.
+const SIDECAR_GLOB: &str = "**/.mermaid/*.mmd";
+const WATCHER_REGISTRATION_ID: &str = "mermaid-sidecar-watcher";
+/// Suffix of a diagram's dark rendition, inserted before its content hash.
+const DARK_SUFFIX: &str = "-dark";
+
+/// Ask the client to report changes to `.mermaid/*.mmd` files, if it supports dynamic registration.
+pub fn register_watcher(
+    connection: &Connection,
+    pending_requests: &mut PendingRequests,
+    initialize_params: &Value,
+) -> Result<()> {
+    let supported = initialize_params
+        .pointer("/capabilities/workspace/didChangeWatchedFiles/dynamicRegistration")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if !supported {
+        debug!("Client cannot watch files; sidecar edits will not refresh images");
+        return Ok(());
+    }
+
+    let options = DidChangeWatchedFilesRegistrationOptions {
+        watchers: vec![FileSystemWatcher {
+            glob_pattern: GlobPattern::String(SIDECAR_GLOB.to_string()),
+            kind: Some(WatchKind::Create | WatchKind::Change),
+        }],
+    };
+    let params = RegistrationParams {
+        registrations: vec![Registration {
+            id: WATCHER_REGISTRATION_ID.to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: Some(serde_json::to_value(options)?),
+        }],
+    };
+
+    let method = "client/registerCapability";
+    pending_requests.send(connection, method, params, PendingRequest::Other { method: method.to_string() })?;
+    info!("Registered watcher for {}", SIDECAR_GLOB);
+    Ok(())
+}
+
+/// Sidecar sources among the reported file changes.
+pub fn changed_sidecars(events: &[FileEvent]) -> Vec<PathBuf> {
+    events
+        .iter()
+        .filter(|event| event.typ != FileChangeType::DELETED)
+        .filter_map(|event| event.uri.to_file_path().ok())
+        .filter(|path| is_sidecar(path))
+        .collect()
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    let in_media_dir = path
+        .parent()
+        .and_then(Path::file_name)
+        .map(|dir| dir == MERMAID_MEDIA_DIR)
+        .unwrap_or(false);
+    in_media_dir && path.to_string_lossy().ends_with(MERMAID_FILE_EXTENSION)
+}
+
+/// Re-render the images rendered from a sidecar source, keeping their names so links stay valid.
+///
+/// Every SVG and PNG of the diagram and its dark rendition is refreshed when present, and
+/// records the new source hash. Images already stamped with the source's hash are left
+/// alone, so the server's own writes cause no re-render. Returns the files rewritten.
+pub fn sync_sidecar(source_path: &Path) -> Result<Vec<PathBuf>> {
+    let media_dir = source_path
+        .parent()
+        .ok_or_else(|| anyhow!("Sidecar has no parent directory: {:?}", source_path))?;
+    let base_name = source_path
+        .file_stem()
+        .ok_or_else(|| anyhow!("Sidecar has no file name: {:?}", source_path))?
+        .to_string_lossy()
+        .to_string();
+    let code = fs::read_to_string(source_path)
+        .map_err(|e| anyhow!("Failed to read {:?}: {}", source_path, e))?;
+    if code.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let hash = content_hash(&code);
+    let settings = config::current();
+    let mut variants = vec![(base_name.clone(), settings.render_options_for(&code))];
+    if let Some(dark_name) = dark_variant_name(&base_name) {
+        variants.push((dark_name, settings.dark_variant().render_options_for(&code)));
+    }
+
+    let mut updated = Vec::new();
+    for (name, options) in variants {
+        for format in [ImageFormat::Svg, ImageFormat::Png] {
+            let image_path = media_dir.join(format!("{}.{}", name, format.extension()));
+            let Ok(existing) = fs::read(&image_path) else {
+                continue;
+            };
+            if recorded_image_source_hash(&existing) == Some(hash.as_str()) {
+                debug!("{:?} is up to date", image_path);
+                continue;
+            }
+
+            let (_, contents) = render_options_to_cache(&code, &options, media_dir, format)?;
+            let contents = match format {
+                ImageFormat::Svg => stamp_source_hash(&String::from_utf8_lossy(&contents), &hash).into_bytes(),
+                ImageFormat::Png => stamp_png_source_hash(&contents, &hash),
+                ImageFormat::Pdf => contents,
+            };
+            fs::write(&image_path, &contents)
+                .map_err(|e| anyhow!("Failed to write {:?}: {}", image_path, e))?;
+            info!("Re-rendered {:?} from {:?}", image_path, source_path);
+            updated.push(image_path);
+        }
+    }
+
+    Ok(updated)
+}
+
+/// `<stem>_<diagram>-dark_<hash>` for a `<stem>_<diagram>_<hash>` base name.
+fn dark_variant_name(base_name: &str) -> Option<String> {
+    let (diagram, hash) = base_name.rsplit_once('_')?;
+    Some(format!("{}{}_{}", diagram, DARK_SUFFIX, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::Url;
+
+    #[test]
+    fn names_the_dark_rendition() {
+        assert_eq!(dark_variant_name("doc_0_0123456789ab").as_deref(), Some("doc_0-dark_0123456789ab"));
+        assert_eq!(dark_variant_name("diagram"), None);
+    }
+
+    #[test]
+    fn picks_sidecar_sources_from_file_events() {
+        let event = |path: &str, typ| FileEvent {
+            uri: Url::from_file_path(path).unwrap(),
+            typ,
+        };
+        let events = [
+            event("/docs/.mermaid/doc_0_abc.mmd", FileChangeType::CHANGED),
+            event("/docs/.mermaid/doc_1_abc.mmd", FileChangeType::DELETED),
+            event("/docs/flow.mmd", FileChangeType::CHANGED),
+            event("/docs/.mermaid/doc_0_abc.svg", FileChangeType::CREATED),
+        ];
+        assert_eq!(changed_sidecars(&events), vec![PathBuf::from("/docs/.mermaid/doc_0_abc.mmd")]);
+    }
+
+    #[test]
+    fn leaves_current_and_unrendered_sidecars_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("doc_0_abc.mmd");
+        fs::write(&source, "pie").unwrap();
+
+        // No SVG was rendered from it
+        assert!(sync_sidecar(&source).unwrap().is_empty());
+
+        // The SVG already records this source
+        let svg = stamp_source_hash("<svg/>", &content_hash("pie"));
+        fs::write(dir.path().join("doc_0_abc.svg"), &svg).unwrap();
+        assert!(sync_sidecar(&source).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(dir.path().join("doc_0_abc.svg")).unwrap(), svg);
+    }
+
+    #[test]
+    fn refreshes_png_outputs() {
+        crate::render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("doc_0_abc.mmd");
+        fs::write(&source, "pie").unwrap();
+
+        // outputFormat png: only the PNG was written
+        let png = dir.path().join("doc_0_abc.png");
+        fs::write(&png, "stale").unwrap();
+        assert_eq!(sync_sidecar(&source).unwrap(), vec![png.clone()]);
+        let synced = fs::read(&png).unwrap();
+        assert!(synced.starts_with(b"\x89PNG"));
+        assert_eq!(recorded_image_source_hash(&synced), Some(content_hash("pie").as_str()));
+        assert!(sync_sidecar(&source).unwrap().is_empty());
+
+        // outputFormat both: a stale SVG refreshes its PNG alongside it
+        let svg = dir.path().join("doc_0_abc.svg");
+        fs::write(&svg, "<svg/>").unwrap();
+        fs::write(&png, "stale").unwrap();
+        assert_eq!(sync_sidecar(&source).unwrap(), vec![svg.clone(), png.clone()]);
+        assert_eq!(recorded_image_source_hash(&fs::read(&svg).unwrap()), Some(content_hash("pie").as_str()));
+        assert!(fs::read(&png).unwrap().starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn check_accepts_synced_png_outputs() {
+        crate::render::use_test_renderer();
+        let dir = tempfile::tempdir().unwrap();
+        let media_dir = dir.path().join(MERMAID_MEDIA_DIR);
+        fs::create_dir(&media_dir).unwrap();
+
+        // Rendered with outputFormat png; the name records the original source's hash
+        let base_name = format!("doc_0_{}", content_hash("pie"));
+        let source = media_dir.join(format!("{}.mmd", base_name));
+        fs::write(&source, "pie").unwrap();
+        fs::write(media_dir.join(format!("{}.png", base_name)), "old").unwrap();
+        fs::write(
+            dir.path().join("doc.md"),
+            format!(
+                "<!-- mermaid-source-file:.mermaid/{0}.mmd-->\n\n![Mermaid Diagram](.mermaid/{0}.png)\n",
+                base_name
+            ),
+        )
+        .unwrap();
+
+        fs::write(&source, "flowchart TD\n    A --> B").unwrap();
+        let (problems, _) = crate::check::check_paths(&[dir.path().to_path_buf()]).unwrap();
+        assert!(problems[0].message.contains("stale"), "{:#?}", problems);
+
+        sync_sidecar(&source).unwrap();
+        let (problems, _) = crate::check::check_paths(&[dir.path().to_path_buf()]).unwrap();
+        assert!(problems.is_empty(), "{:#?}", problems);
+    }
+}
@@ -0,0 +1,402 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+use crate::render::{mmdc_path, ImageFormat, RenderOptions, Renderer};
+
+const WORKER_SCRIPT: &str = include_str!("mermaid-worker.mjs");
+
+/// How long to wait for the browser to start before giving up on the worker.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a single diagram may take before the worker is considered hung.
+const RENDER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the worker may take to close the browser and exit before it is killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One line written by the worker on stdout.
+#[derive(Debug, Deserialize)]
+struct WorkerResponse {
+    id: Option<u64>,
+    #[serde(default)]
+    ready: bool,
+    svg: Option<String>,
+    /// Base64-encoded PNG or PDF.
+    data: Option<String>,
+    error: Option<String>,
+}
+
+/// A running `node mermaid-worker.mjs` process.
+struct WorkerProcess {
+    child: Child,
+    stdin: ChildStdin,
+    responses: Receiver<String>,
+    // Keeps the extracted worker script alive for as long as the process runs
+    _script_dir: Option<TempDir>,
+}
+
+impl WorkerProcess {
+    fn spawn() -> Result<Self> {
+        let node = which::which("node").map_err(|_| anyhow!("node not found in PATH"))?;
+        let cli_dir = mermaid_cli_dir()?;
+
+        let script_dir = tempfile::tempdir()
+            .map_err(|e| anyhow!("Failed to create worker script dir: {}", e))?;
+        let script_path = script_dir.path().join("mermaid-worker.mjs");
+        fs::write(&script_path, WORKER_SCRIPT)
+            .map_err(|e| anyhow!("Failed to write worker script: {}", e))?;
+
+        info!("Starting Mermaid render worker (mermaid-cli at {:?})", cli_dir);
+        let mut command = Command::new(node);
+        command.arg(&script_path).env("MERMAID_CLI_DIR", &cli_dir);
+        Self::start(command, Some(script_dir))
+    }
+
+    /// Start a worker speaking the line protocol and wait until it reports ready.
+    fn start(mut command: Command, script_dir: Option<TempDir>) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| anyhow!("Failed to start render worker: {}", e))?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("Render worker has no stdin"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("Render worker has no stdout"))?;
+
+        // Read stdout on a separate thread so requests can time out
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(|line| line.ok()) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut process = Self {
+            child,
+            stdin,
+            responses,
+            _script_dir: script_dir,
+        };
+
+        let ready = process.next_response(STARTUP_TIMEOUT)?;
+        if !ready.ready {
+            process.kill();
+            return Err(anyhow!("Render worker did not report ready"));
+        }
+
+        info!("Mermaid render worker ready");
+        Ok(process)
+    }
+
+    fn next_response(&mut self, timeout: Duration) -> Result<WorkerResponse> {
+        let line = match self.responses.recv_timeout(timeout) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(anyhow!("Render worker timed out after {:?}", timeout))
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("Render worker exited")),
+        };
+
+        serde_json::from_str(&line)
+            .map_err(|e| anyhow!("Invalid response from render worker: {}", e))
+    }
+
+    fn render(
+        &mut self,
+        id: u64,
+        mermaid_code: &str,
+        options: &RenderOptions,
+        format: ImageFormat,
+    ) -> Result<WorkerResult> {
+        let config: serde_json::Value = serde_json::from_str(&options.config)
+            .map_err(|e| anyhow!("Invalid Mermaid config: {}", e))?;
+        let request = json!({
+            "id": id,
+            "code": mermaid_code,
+            "config": config,
+            "background": options.background,
+            "format": format.extension(),
+            "scale": options.scale,
+        });
+
+        writeln!(self.stdin, "{}", request)
+            .and_then(|_| self.stdin.flush())
+            .map_err(|e| anyhow!("Failed to send diagram to render worker: {}", e))?;
+
+        loop {
+            let response = self.next_response(RENDER_TIMEOUT)?;
+            if response.id != Some(id) {
+                // A late answer to a request that already timed out
+                debug!("Ignoring stale worker response {:?}", response.id);
+                continue;
+            }
+
+            if let Some(error) = response.error {
+                return Ok(WorkerResult::Failed(error));
+            }
+            return Ok(match (format, response.svg, response.data) {
+                (ImageFormat::Svg, Some(svg), _) => WorkerResult::Rendered(svg.into_bytes()),
+                (ImageFormat::Png | ImageFormat::Pdf, _, Some(data)) => {
+                    base64::engine::general_purpose::STANDARD
+                        .decode(data)
+                        .map(WorkerResult::Rendered)
+                        .unwrap_or_else(|e| WorkerResult::Failed(format!("invalid output data: {}", e)))
+                }
+                _ => WorkerResult::Failed("empty response".to_string()),
+            });
+        }
+    }
+
+    fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    /// Close stdin so the worker closes the browser, killing it only if it does not exit in time.
+    ///
+    /// Returns whether the worker exited on its own.
+    fn stop(self, timeout: Duration) -> bool {
+        let WorkerProcess { mut child, stdin, .. } = self;
+        drop(stdin);
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match child.try_wait() {
+                Ok(Some(_)) => return true,
+                Ok(None) => thread::sleep(Duration::from_millis(50)),
+                Err(_) => break,
+            }
+        }
+
+        warn!("Render worker did not exit within {:?}; killing it", timeout);
+        let _ = child.kill();
+        let _ = child.wait();
+        false
+    }
+}
+
+enum WorkerResult {
+    Rendered(Vec<u8>),
+    /// The diagram itself failed to render; the worker is still healthy.
+    Failed(String),
+}
+
+/// Renderer backed by one long-lived headless browser process.
+///
+/// The worker is started lazily on the first diagram, restarted if it crashes or
+/// hangs, and stopped by [`Renderer::shutdown`].
+pub struct WorkerRenderer {
+    process: Mutex<Option<WorkerProcess>>,
+    next_id: AtomicU64,
+    spawn: Box<dyn Fn() -> Result<WorkerProcess> + Send + Sync>,
+}
+
+impl WorkerRenderer {
+    pub fn new() -> Self {
+        Self::with_spawner(WorkerProcess::spawn)
+    }
+
+    fn with_spawner(spawn: impl Fn() -> Result<WorkerProcess> + Send + Sync + 'static) -> Self {
+        Self {
+            process: Mutex::new(None),
+            next_id: AtomicU64::new(1),
+            spawn: Box::new(spawn),
+        }
+    }
+
+    /// Render through the worker, restarting it once if it has died or hung.
+    fn render_format(
+        &self,
+        mermaid_code: &str,
+        options: &RenderOptions,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>> {
+        let mut guard = self
+            .process
+            .lock()
+            .map_err(|_| anyhow!("Render worker lock poisoned"))?;
+
+        // One retry with a fresh process if the current one has died
+        for attempt in 0..2 {
+            if guard.is_none() {
+                *guard = Some((self.spawn)()?);
+            }
+            let process = guard.as_mut().expect("worker was just started");
+
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            match process.render(id, mermaid_code, options, format) {
+                Ok(WorkerResult::Rendered(output)) => return Ok(output),
+                Ok(WorkerResult::Failed(error)) => return Err(anyhow!("mermaid error: {}", error)),
+                Err(e) => {
+                    warn!("Render worker failed (attempt {}): {}; restarting", attempt + 1, e);
+                    process.kill();
+                    *guard = None;
+                    if attempt == 1 {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        unreachable!("render loop always returns")
+    }
+}
+
+impl Renderer for WorkerRenderer {
+    fn name(&self) -> &'static str {
+        "worker"
+    }
+
+    fn render_svg(&self, mermaid_code: &str, options: &RenderOptions) -> Result<String> {
+        let svg = self.render_format(mermaid_code, options, ImageFormat::Svg)?;
+        String::from_utf8(svg).map_err(|e| anyhow!("Render worker returned invalid SVG: {}", e))
+    }
+
+    fn render_png(&self, mermaid_code: &str, options: &RenderOptions) -> Result<Vec<u8>> {
+        self.render_format(mermaid_code, options, ImageFormat::Png)
+    }
+
+    fn render_pdf(&self, mermaid_code: &str, options: &RenderOptions) -> Result<Vec<u8>> {
+        self.render_format(mermaid_code, options, ImageFormat::Pdf)
+    }
+
+    fn shutdown(&self) {
+        if let Ok(mut guard) = self.process.lock() {
+            if let Some(process) = guard.take() {
+                info!("Stopping Mermaid render worker");
+                process.stop(SHUTDOWN_TIMEOUT);
+            }
+        }
+    }
+}
+
+/// Locate the `@mermaid-js/mermaid-cli` package that provides `mmdc`.
+fn mermaid_cli_dir() -> Result<PathBuf> {
+    let mmdc = mmdc_path()?;
+    // npm installs mmdc as a symlink to <package>/src/cli.js
+    let resolved = mmdc.canonicalize().unwrap_or(mmdc);
+
+    resolved
+        .ancestors()
+        .find(|dir| dir.join("package.json").is_file() && dir.join("src").join("index.js").is_file())
+        .map(|dir| dir.to_path_buf())
+        .ok_or_else(|| {
+            anyhow!(
+                "Could not locate the @mermaid-js/mermaid-cli package from {:?}",
+                resolved
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    /// A worker that answers every request with a stale response, then the real one.
+    #[cfg(unix)]
+    const FAKE_WORKER: &str = r#"
+        echo '{"ready":true}'
+        while read -r line; do
+            id=$(printf '%s' "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+            echo '{"id":999999,"svg":"<svg>stale</svg>"}'
+            echo "{\"id\":$id,\"svg\":\"<svg>$id</svg>\"}"
+        done
+    "#;
+
+    /// A worker that exits as soon as it receives a request.
+    #[cfg(unix)]
+    const CRASHING_WORKER: &str = r#"
+        echo '{"ready":true}'
+        read -r line
+        exit 1
+    "#;
+
+    #[cfg(unix)]
+    fn fake_worker(script: &str) -> Result<WorkerProcess> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        WorkerProcess::start(command, None)
+    }
+
+    #[test]
+    fn parses_worker_responses() {
+        let ready: WorkerResponse = serde_json::from_str(r#"{"ready":true}"#).unwrap();
+        assert!(ready.ready);
+        assert_eq!(ready.id, None);
+
+        let svg: WorkerResponse = serde_json::from_str(r#"{"id":3,"svg":"<svg/>"}"#).unwrap();
+        assert_eq!((svg.id, svg.svg.as_deref(), svg.ready), (Some(3), Some("<svg/>"), false));
+
+        let png: WorkerResponse = serde_json::from_str(r#"{"id":4,"data":"iVBORw=="}"#).unwrap();
+        assert_eq!(png.data.as_deref(), Some("iVBORw=="));
+
+        let error: WorkerResponse = serde_json::from_str(r#"{"id":null,"error":"invalid request"}"#).unwrap();
+        assert_eq!((error.id, error.error.as_deref()), (None, Some("invalid request")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_responses_to_other_requests() {
+        let mut process = fake_worker(FAKE_WORKER).unwrap();
+        let options = RenderOptions::default();
+        for id in [7, 8] {
+            match process.render(id, "pie", &options, ImageFormat::Svg).unwrap() {
+                WorkerResult::Rendered(svg) => assert_eq!(svg, format!("<svg>{}</svg>", id).into_bytes()),
+                WorkerResult::Failed(error) => panic!("render failed: {}", error),
+            }
+        }
+        assert!(process.stop(SHUTDOWN_TIMEOUT));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restarts_the_worker_after_it_exits() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let counter = spawned.clone();
+        let renderer = WorkerRenderer::with_spawner(move || {
+            // The first worker dies mid-request; its replacement is healthy
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => fake_worker(CRASHING_WORKER),
+                _ => fake_worker(FAKE_WORKER),
+            }
+        });
+
+        let svg = renderer.render_svg("pie", &RenderOptions::default()).unwrap();
+        assert!(svg.starts_with("<svg>"));
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+        renderer.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_a_worker_that_ignores_shutdown() {
+        let process = fake_worker("echo '{\"ready\":true}'; exec sleep 30").unwrap();
+        assert!(!process.stop(Duration::from_millis(200)));
+    }
+}
@@ -4,7 +4,8 @@ use std::{
     process::Command,
 };
 use zed_extension_api::{
-    self as zed, Architecture, DownloadedFileType, LanguageServerId, Os, Result,
+    self as zed, serde_json, settings::LspSettings, Architecture, DownloadedFileType,
+    LanguageServerId, Os, Result,
 };
 
 const GITHUB_REPOSITORY: &str = "dawsh2/zed-mermaid-preview";
@@ -12,11 +13,16 @@ const CACHE_ROOT: &str = "mermaid-lsp-cache";
 
 struct MermaidPreviewExtension {
     lsp_path: Option<String>,
+    /// `--version` output of the cached binary: server, mmdc and config versions.
+    lsp_version: Option<String>,
 }
 
 impl zed::Extension for MermaidPreviewExtension {
     fn new() -> Self {
-        let mut extension = Self { lsp_path: None };
+        let mut extension = Self {
+            lsp_path: None,
+            lsp_version: None,
+        };
 
         // Pre-download LSP binary during extension initialization
         // This prevents delay on first file open
@@ -40,15 +46,43 @@ impl zed::Extension for MermaidPreviewExtension {
         let lsp_path = self.get_lsp_path(worktree, language_server_id)?;
 
         eprintln!("Starting Mermaid LSP at: {}", lsp_path);
+        if let Some(version) = &self.lsp_version {
+            eprintln!("Mermaid LSP reports: {}", version.replace('\n', ", "));
+        }
         Ok(zed::Command {
             command: lsp_path,
             args: vec![],
             env: Default::default(),
         })
     }
+
+    fn language_server_initialization_options(
+        &mut self,
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<serde_json::Value>> {
+        Self::mermaid_settings(language_server_id, worktree)
+    }
+
+    fn language_server_workspace_configuration(
+        &mut self,
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<serde_json::Value>> {
+        Self::mermaid_settings(language_server_id, worktree)
+    }
 }
 
 impl MermaidPreviewExtension {
+    /// The user's `lsp.mermaid.settings` (theme, background, renderer, ...).
+    fn mermaid_settings(
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> Result<Option<serde_json::Value>> {
+        let settings = LspSettings::for_worktree(language_server_id.as_ref(), worktree)?;
+        Ok(settings.settings)
+    }
+
     /// Initialize LSP binary during extension startup to prevent first-use delay
     fn initialize_lsp_binary(&mut self) -> Result<()> {
         eprintln!("=== Initializing Mermaid LSP binary during extension load ===");
@@ -333,8 +367,13 @@ impl MermaidPreviewExtension {
             {
                 Ok(output) => {
                     if output.status.success() {
-                        let version = String::from_utf8_lossy(&output.stdout).trim();
-                        eprintln!("✅ Using existing LSP version: {} ({})", release.version, version);
+                        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                        eprintln!(
+                            "✅ Using existing LSP version: {} ({})",
+                            release.version,
+                            version.replace('\n', ", ")
+                        );
+                        self.lsp_version = Some(version);
                         zed::set_language_server_installation_status(
                             language_server_id,
                             &zed::LanguageServerInstallationStatus::None,